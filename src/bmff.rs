@@ -0,0 +1,160 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+/// Maximum size of a box that is read into memory (boxes containing meta data are usually much smaller)
+const MAX_BOX_SIZE: u64 = 64 << 20;
+
+/// Box of an ISO base media file (as used by MP4, MOV and HEIF files), given by its type and its payload (without the header).
+pub struct BmffBox<'a> {
+    pub box_type: [u8; 4],
+    pub payload: &'a [u8],
+}
+
+pub fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = data.get(offset..offset + 2).context("Unexpected end of box!")?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..offset + 4).context("Unexpected end of box!")?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+pub fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    Ok((read_u32(data, offset)? as u64) << 32 | read_u32(data, offset + 4)? as u64)
+}
+
+/// Returns whether the given data (the first bytes of a file) look like the start of an ISO base media file, i.e., start with a box of a
+/// type that usually appears on the top level.
+pub fn is_bmff(header: &[u8]) -> bool {
+    matches!(
+        header.get(4..8),
+        Some(b"ftyp") | Some(b"moov") | Some(b"mdat") | Some(b"wide") | Some(b"free") | Some(b"skip")
+    )
+}
+
+/// Parses the header of the box starting at the given data, returning the type, the header length and the total length of the box.
+fn parse_box_header(data: &[u8], remaining: u64) -> Result<([u8; 4], u64, u64)> {
+    let size = read_u32(data, 0)? as u64;
+    let box_type = [data[4], data[5], data[6], data[7]];
+
+    let (header_length, size) = match size {
+        0 => (8, remaining),
+        1 => (16, read_u64(data, 8)?),
+        _ => (8, size),
+    };
+
+    if size < header_length || size > remaining {
+        bail!("Invalid size of box \"{}\"!", String::from_utf8_lossy(&box_type));
+    }
+
+    Ok((box_type, header_length, size))
+}
+
+/// Parses the boxes contained in the given data (e.g., the payload of a container box).
+pub fn parse_boxes(data: &[u8]) -> Result<Vec<BmffBox<'_>>> {
+    let mut boxes = vec![];
+    let mut pos = 0;
+
+    while pos + 8 <= data.len() {
+        let (box_type, header_length, size) = parse_box_header(&data[pos..], (data.len() - pos) as u64)?;
+        boxes.push(BmffBox {
+            box_type,
+            payload: &data[pos + header_length as usize..pos + size as usize],
+        });
+        pos += size as usize;
+    }
+
+    Ok(boxes)
+}
+
+/// Returns the payload of the box found by descending along the given box types from the given data (e.g., [b"trak", b"tkhd"]).
+pub fn find_box<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Result<Option<&'a [u8]>> {
+    let Some((first, rest)) = path.split_first() else {
+        return Ok(Some(data));
+    };
+
+    match parse_boxes(data)?.into_iter().find(|b| &b.box_type == *first) {
+        Some(b) if rest.is_empty() => Ok(Some(b.payload)),
+        Some(b) => find_box(container_payload(&b), rest),
+        None => Ok(None),
+    }
+}
+
+/// Returns the part of the given box payload that contains the child boxes. This is the whole payload except for the meta box, which is
+/// a full box (starting with version and flags) in ISO base media files, but not in QuickTime files.
+pub fn container_payload<'a>(b: &BmffBox<'a>) -> &'a [u8] {
+    if &b.box_type == b"meta" && b.payload.get(4..8) != Some(b"hdlr") {
+        b.payload.get(4..).unwrap_or_default()
+    } else {
+        b.payload
+    }
+}
+
+/// Splits the payload of a full box into its version and the remaining payload (skipping the flags).
+pub fn full_box_payload(payload: &[u8]) -> Result<(u8, &[u8])> {
+    if payload.len() < 4 {
+        bail!("Unexpected end of box!");
+    }
+    Ok((payload[0], &payload[4..]))
+}
+
+/// Reads the payload of the first top-level box of the given type from the given file, skipping all other boxes (e.g., the potentially
+/// large media data) without reading them.
+pub fn read_top_level_box(file: &mut File, box_type: &[u8; 4]) -> Result<Option<Vec<u8>>> {
+    let file_length = file.metadata()?.len();
+    let mut pos = 0;
+
+    while pos + 8 <= file_length {
+        let mut header = [0; 16];
+        file.seek(SeekFrom::Start(pos))?;
+        let header_bytes = (file_length - pos).min(16) as usize;
+        file.read_exact(&mut header[..header_bytes])?;
+
+        let (cur_box_type, header_length, size) = parse_box_header(&header, file_length - pos)?;
+        if &cur_box_type == box_type {
+            if size - header_length > MAX_BOX_SIZE {
+                bail!("Box \"{}\" is too large!", String::from_utf8_lossy(box_type));
+            }
+
+            let mut payload = vec![0; (size - header_length) as usize];
+            file.seek(SeekFrom::Start(pos + header_length))?;
+            match file.read_exact(&mut payload) {
+                Ok(()) => return Ok(Some(payload)),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => bail!("File is truncated!"),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        pos += size;
+    }
+
+    Ok(None)
+}
+
+/// Validates the top-level structure of the given file by walking the headers of all top-level boxes, which have to lie completely within
+/// the file (a truncated file usually ends within the media data box). Returns the types of the top-level boxes.
+pub fn read_top_level_box_types(file: &mut File) -> Result<Vec<[u8; 4]>> {
+    let file_length = file.metadata()?.len();
+    let mut box_types = vec![];
+    let mut pos = 0;
+
+    while pos < file_length {
+        if file_length - pos < 8 {
+            bail!("File ends with an incomplete box header!");
+        }
+
+        let mut header = [0; 16];
+        file.seek(SeekFrom::Start(pos))?;
+        let header_bytes = (file_length - pos).min(16) as usize;
+        file.read_exact(&mut header[..header_bytes])?;
+
+        let (box_type, _, size) = parse_box_header(&header, file_length - pos)
+            .with_context(|| format!("Invalid box at offset {} (file may be truncated)!", pos))?;
+        box_types.push(box_type);
+        pos += size;
+    }
+
+    Ok(box_types)
+}
@@ -0,0 +1,72 @@
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::process;
+
+/// Contents of a cache file (stored in the root directory) together with the version of the cache, which has to be incremented whenever
+/// the cached results become invalid (e.g., since the tool can decode more files)
+#[derive(Deserialize, Serialize)]
+struct CacheFile<T> {
+    version: u64,
+
+    #[serde(flatten)]
+    contents: T,
+}
+
+/// Reads the contents of the cache file with the given name in the root directory. Returns None if there is no (valid) cache file or if it
+/// has been written with another version of the cache.
+pub fn read_cache_file<T: DeserializeOwned>(root_dir: &Path, file_name: &str, version: u64) -> Option<T> {
+    let filepath = root_dir.join(file_name);
+    let cache = match fs::read(&filepath) {
+        Ok(data) => serde_json::from_slice::<CacheFile<T>>(&data),
+        Err(_) => return None,
+    };
+
+    match cache {
+        Ok(cache) if cache.version == version => Some(cache.contents),
+        Ok(_) => {
+            debug!(
+                "{}: Cache has been written by another version of the tool, discarding it.",
+                file_name
+            );
+            None
+        }
+        Err(e) => {
+            warn!("Could not parse cache at {}: {}", filepath.display(), e);
+            None
+        }
+    }
+}
+
+/// Writes the given contents to the cache file with the given name in the root directory, replacing the file atomically. Since not all
+/// commands writing caches lock the collection, the temporary file is unique to the process, so that concurrent runs do not write to the
+/// same temporary file (the last run replacing the cache file wins).
+pub fn write_cache_file<T: Serialize>(root_dir: &Path, file_name: &str, version: u64, contents: T) -> Result<()> {
+    let cache = CacheFile { version, contents };
+
+    let temp_filepath = root_dir.join(format!("{}.{}.tmp", file_name, process::id()));
+    let write_temp_file = || -> Result<()> {
+        let mut file = File::create(&temp_filepath)?;
+        file.write_all(&serde_json::to_vec_pretty(&cache)?)?;
+        file.sync_all()?;
+        Ok(())
+    };
+    if let Err(e) = write_temp_file() {
+        let _ = fs::remove_file(&temp_filepath);
+        return Err(e).with_context(|| format!("Could not write {}!", temp_filepath.display()));
+    }
+
+    fs::rename(&temp_filepath, root_dir.join(file_name))
+        .with_context(|| format!("Could not replace {}!", file_name))?;
+
+    // Sync directory to make the rename durable (not possible on all platforms, hence errors are ignored)
+    if let Ok(dir) = File::open(root_dir) {
+        let _ = dir.sync_all();
+    }
+
+    Ok(())
+}
@@ -1,91 +1,507 @@
-use log::warn;
-use std::collections::HashMap;
-use std::path::Path;
-
-use crate::collection::{calc_photo_hash, get_canonical_photo_filename};
-use crate::index::{Index, IndexEntry};
-
-/// Checks for duplicates (according to the hash) among the photos that are part of the index. Returns whether duplicates have been found.
-pub fn check_for_duplicates(index: &Index) -> bool {
-    let mut hashes_to_files: HashMap<&str, Vec<&IndexEntry>> = HashMap::new();
-    for photo in index.photos.iter() {
-        if let Some(list) = hashes_to_files.get_mut(photo.filehash.as_str()) {
-            list.push(photo);
-        } else {
-            hashes_to_files.insert(&photo.filehash, vec![photo]);
+use anyhow::{Context, Result};
+use html_escape::encode_safe;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::{self, Write as _};
+use std::path::{Path, PathBuf};
+
+use crate::cache::{read_cache_file, write_cache_file};
+use crate::collection::{
+    calc_photo_hashes, find_dangling_symlinks, get_canonical_photo_directories, get_canonical_photo_filenames,
+    get_companion_filename, group_photo_files, scan_collection_files, verify_photos_decodable, Decodability, Photo,
+};
+use crate::index::{Index, IndexEntry, Severity};
+
+/// All checks that can be run by the check command (in the order they are run). New checks only have to be added here to be available.
+pub static CHECKS: &[&dyn Check] = &[
+    &DuplicatesCheck,
+    &HashesCheck,
+    &NamingCheck,
+    &DirectoriesCheck,
+    &OrphanedSidecarsCheck,
+    &DanglingSymlinksCheck,
+    &DecodabilityCheck,
+];
+
+/// Name of the file (in the root directory) that caches the hashes of the files that have been verified to be decodable
+const DECODABILITY_CACHE_FILE_NAME: &str = "photo_organizer_decodability_cache.json";
+
+/// Version of the decodability cache, which has to be incremented whenever the verification becomes stricter (invalidating the cache)
+const DECODABILITY_CACHE_VERSION: u64 = 1;
+
+/// Integrity check of a photo collection.
+pub trait Check: Sync {
+    /// Name of the check (as used in the check configuration and on the command line)
+    fn name(&self) -> &'static str;
+
+    /// Severity of the check if it is not configured otherwise
+    fn default_severity(&self) -> Severity {
+        Severity::Error
+    }
+
+    /// Runs the check and returns its findings. An error is returned if the check could not be run at all. In dry-run mode, the check must
+    /// not write anything (e.g., caches) to the filesystem.
+    fn run(&self, root_dir: &Path, index: &Index, dry_run: bool) -> Result<Vec<Finding>>;
+}
+
+/// Returns the names of all checks.
+pub fn check_names() -> Vec<&'static str> {
+    CHECKS.iter().map(|c| c.name()).collect()
+}
+
+/// Issue found by one of the checks.
+#[derive(Serialize)]
+pub struct Finding {
+    /// Name of the check that found the issue
+    pub check: &'static str,
+
+    /// Severity configured for the check
+    pub severity: Severity,
+
+    /// Path of the affected file (relative to the root directory)
+    pub path: PathBuf,
+
+    pub message: String,
+
+    /// Expected value (e.g., the recorded hash or the filename according to the naming scheme), if applicable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+
+    /// Actual value (e.g., the current hash or filename), if applicable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual: Option<String>,
+
+    /// How the issue can be fixed (e.g., by running a certain command), if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggested_fix: Option<String>,
+}
+
+impl Finding {
+    fn new(check: &dyn Check, path: &Path, message: String) -> Finding {
+        Finding {
+            check: check.name(),
+            severity: Severity::Error,
+            path: path.to_owned(),
+            message,
+            expected: None,
+            actual: None,
+            suggested_fix: None,
         }
     }
 
-    let mut found_duplicates = false;
+    fn with_values(mut self, expected: impl Into<String>, actual: impl Into<String>) -> Finding {
+        self.expected = Some(expected.into());
+        self.actual = Some(actual.into());
+        self
+    }
+
+    fn with_fix(mut self, suggested_fix: &str) -> Finding {
+        self.suggested_fix = Some(suggested_fix.into());
+        self
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)?;
+        if let Some(suggested_fix) = &self.suggested_fix {
+            write!(f, " ({})", suggested_fix)?;
+        }
+        Ok(())
+    }
+}
 
-    for (hash, photos) in hashes_to_files.iter().filter(|(_, photos)| photos.len() > 1) {
-        found_duplicates = true;
+/// Renders the given findings as a self-contained HTML report with one table row per finding.
+pub fn render_findings_html(root_dir: &Path, findings: &[Finding]) -> Result<String> {
+    let mut html = String::new();
+    writeln!(&mut html, "<!DOCTYPE html>")?;
+    writeln!(&mut html, "<html lang=\"en\">")?;
+    writeln!(&mut html, "<head>")?;
+    writeln!(&mut html, "<meta charset=\"utf-8\">")?;
+    writeln!(
+        &mut html,
+        "<title>Check Report for {}</title>",
+        encode_safe(&root_dir.display().to_string())
+    )?;
+    writeln!(
+        &mut html,
+        "<style>table {{ border-collapse: collapse }} td, th {{ border: 1px solid #ccc; padding: 4px; text-align: left }} \
+         .error {{ color: #b00 }} .warning {{ color: #a60 }}</style>"
+    )?;
+    writeln!(&mut html, "</head>")?;
+    writeln!(&mut html, "<body>")?;
+    writeln!(
+        &mut html,
+        "<h1>Check Report for {}</h1>",
+        encode_safe(&root_dir.display().to_string())
+    )?;
 
-        warn!("These files seem to be duplicates (hash: {}):", hash);
-        for photo in photos {
-            warn!("  {}", photo.filepath.display());
+    if findings.is_empty() {
+        writeln!(&mut html, "<p>No issues found.</p>")?;
+    } else {
+        writeln!(&mut html, "<p>{} issues found.</p>", findings.len())?;
+        writeln!(&mut html, "<table>")?;
+        writeln!(
+            &mut html,
+            "<tr><th>Severity</th><th>Check</th><th>Path</th><th>Message</th><th>Suggested fix</th></tr>"
+        )?;
+        for finding in findings {
+            let severity = match finding.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+                Severity::Ignore => "ignore",
+            };
+            writeln!(
+                &mut html,
+                "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                severity,
+                severity,
+                finding.check,
+                encode_safe(&finding.path.display().to_string()),
+                encode_safe(&finding.message),
+                encode_safe(finding.suggested_fix.as_deref().unwrap_or_default())
+            )?;
         }
+        writeln!(&mut html, "</table>")?;
     }
 
-    found_duplicates
+    writeln!(&mut html, "</body>")?;
+    writeln!(&mut html, "</html>")?;
+
+    Ok(html)
 }
 
-/// Rehashes all photos in the index and checks whether the actual hash matches the recorded one. Returns whether for any photo there was a
-/// discrepancy (or any photo could not be re-hashed for checking).
-pub fn check_hashes(root_dir: &Path, index: &Index) -> bool {
-    let mut found_deviation = false;
-
-    for photo in index.photos.iter() {
-        let maybe_actual_hash = calc_photo_hash(&root_dir.join(&photo.filepath));
-        match maybe_actual_hash {
-            Ok(actual_hash) => {
-                if photo.filehash != actual_hash {
-                    found_deviation = true;
-                    warn!(
-                        "{}: Hash does not match (recorded {} but was {})!",
-                        photo.filepath.display(),
-                        photo.filehash,
-                        actual_hash
-                    );
-                }
+/// Checks for duplicates (according to the hash) among the photos that are part of the index. Reports each file that has the same hash as
+/// another file.
+struct DuplicatesCheck;
+
+impl Check for DuplicatesCheck {
+    fn name(&self) -> &'static str {
+        "duplicates"
+    }
+
+    fn run(&self, _root_dir: &Path, index: &Index, _dry_run: bool) -> Result<Vec<Finding>> {
+        let mut hashes_to_files: HashMap<&str, Vec<&IndexEntry>> = HashMap::new();
+        for photo in index.photos.iter() {
+            if let Some(list) = hashes_to_files.get_mut(photo.filehash.as_str()) {
+                list.push(photo);
+            } else {
+                hashes_to_files.insert(&photo.filehash, vec![photo]);
             }
-            Err(e) => {
-                found_deviation = true;
-                warn!(
-                    "Skipping file {}: Could not re-hash the file. The error was: {}",
-                    photo.filepath.display(),
-                    e
+        }
+
+        let mut duplicates: Vec<(&str, Vec<&IndexEntry>)> = hashes_to_files
+            .into_iter()
+            .filter(|(_, photos)| photos.len() > 1)
+            .collect();
+        duplicates.sort_unstable_by(|(_, a), (_, b)| a[0].filepath.cmp(&b[0].filepath));
+
+        let mut findings = vec![];
+        for (hash, photos) in duplicates {
+            for photo in photos.iter() {
+                let others: Vec<String> = photos
+                    .iter()
+                    .filter(|p| p.filepath != photo.filepath)
+                    .map(|p| p.filepath.display().to_string())
+                    .collect();
+                findings.push(
+                    Finding::new(
+                        self,
+                        &photo.filepath,
+                        format!("Seems to be a duplicate of {} (hash: {})", others.join(", "), hash),
+                    )
+                    .with_fix("delete all but one of the files"),
                 );
             }
         }
+
+        Ok(findings)
+    }
+}
+
+/// Rehashes all photos in the index and checks whether the actual hash matches the recorded one. Reports each photo whose hash deviates (or
+/// that could not be re-hashed for checking).
+struct HashesCheck;
+
+impl Check for HashesCheck {
+    fn name(&self) -> &'static str {
+        "hashes"
     }
 
-    found_deviation
+    fn run(&self, root_dir: &Path, index: &Index, _dry_run: bool) -> Result<Vec<Finding>> {
+        let mut findings = vec![];
+
+        // Hash all photos in parallel first and evaluate the results afterwards in order to keep the output deterministic
+        let filepaths: Vec<PathBuf> = index.photos.iter().map(|p| p.filepath.clone()).collect();
+        let actual_hashes = calc_photo_hashes(root_dir, &filepaths);
+
+        for (photo, maybe_actual_hash) in index.photos.iter().zip(actual_hashes) {
+            match maybe_actual_hash {
+                Ok(actual_hash) => {
+                    if photo.filehash != actual_hash {
+                        findings.push(
+                            Finding::new(
+                                self,
+                                &photo.filepath,
+                                format!(
+                                    "Hash does not match (recorded {} but was {})!",
+                                    photo.filehash, actual_hash
+                                ),
+                            )
+                            .with_values(&photo.filehash, actual_hash)
+                            .with_fix(
+                                "restore the file from a backup, or run \"update\" if it has been modified on purpose",
+                            ),
+                        );
+                    }
+                }
+                Err(e) => {
+                    findings.push(Finding::new(
+                        self,
+                        &photo.filepath,
+                        format!("Could not re-hash the file: {}", e),
+                    ));
+                }
+            }
+        }
+
+        Ok(findings)
+    }
 }
 
-/// Checks whether all photos in the index are compliant with the naming scheme set in the index. Returns whether the name of any photo
-/// deviates from the naming scheme.
-pub fn check_photo_naming(root_dir: &Path, index: &Index) -> bool {
-    let mut found_misnamed_file = false;
-
-    for photo in index.photos.iter() {
-        let maybe_cfn = get_canonical_photo_filename(&root_dir.join(&photo.filepath), &index.user_config);
-        match maybe_cfn {
-            Ok(cfn) => {
-                if cfn != photo.filepath.file_name().unwrap_or_default().to_string_lossy() {
-                    warn!("{}: Should be named {}", photo.filepath.display(), cfn);
+/// Checks whether all photos in the index are compliant with the naming scheme set in the index. Reports each photo (or companion) whose
+/// name deviates from the naming scheme or whose correct name could not be determined.
+struct NamingCheck;
+
+impl Check for NamingCheck {
+    fn name(&self) -> &'static str {
+        "naming"
+    }
+
+    fn run(&self, root_dir: &Path, index: &Index, _dry_run: bool) -> Result<Vec<Finding>> {
+        let mut findings = vec![];
+
+        let photos = get_indexed_photos(index);
+        let filepaths: Vec<PathBuf> = photos.iter().map(|p| p.relative_path.clone()).collect();
+        let canonical_names = get_canonical_photo_filenames(root_dir, &filepaths, index);
+
+        for (photo, maybe_cfn) in photos.iter().zip(canonical_names) {
+            match maybe_cfn {
+                Ok(cfn) => {
+                    let filename = photo.relative_path.file_name().unwrap_or_default().to_string_lossy();
+                    if cfn != filename {
+                        findings.push(
+                            Finding::new(self, &photo.relative_path, format!("Should be named {}", cfn))
+                                .with_values(&cfn, filename)
+                                .with_fix("run \"rename\""),
+                        );
+                    }
+
+                    for companion in photo.companions.iter() {
+                        let companion_name = get_companion_filename(&photo.relative_path, companion, &cfn);
+                        let filename = companion.file_name().unwrap_or_default().to_string_lossy();
+                        if companion_name != filename {
+                            findings.push(
+                                Finding::new(self, companion, format!("Should be named {}", companion_name))
+                                    .with_values(companion_name, filename)
+                                    .with_fix("run \"rename\""),
+                            );
+                        }
+                    }
+                }
+                Err(e) => {
+                    findings.push(Finding::new(
+                        self,
+                        &photo.relative_path,
+                        format!("Error while trying to determine correct filename: {}", e),
+                    ));
                 }
             }
-            Err(e) => {
-                found_misnamed_file = true;
-                warn!(
-                    "Error while trying to determine correct filename for {}: {}",
-                    photo.filepath.display(),
-                    e
-                );
+        }
+
+        Ok(findings)
+    }
+}
+
+/// Checks whether all photos in the index are stored in the directory given by the directory naming scheme set in the index (if any).
+/// Reports each photo that is stored in another directory.
+struct DirectoriesCheck;
+
+impl Check for DirectoriesCheck {
+    fn name(&self) -> &'static str {
+        "directories"
+    }
+
+    fn run(&self, root_dir: &Path, index: &Index, _dry_run: bool) -> Result<Vec<Finding>> {
+        let Some(directory_scheme) = index.user_config.directory_naming_scheme.as_deref() else {
+            debug!("No directory naming scheme configured, skipping check of photo directories.");
+            return Ok(vec![]);
+        };
+
+        let mut findings = vec![];
+
+        // Note: Companions are stored in the same directory as their photo by definition, so only the photos themselves have to be checked
+        let filepaths: Vec<PathBuf> = get_indexed_photos(index).into_iter().map(|p| p.relative_path).collect();
+        let canonical_dirs = get_canonical_photo_directories(root_dir, &filepaths, index, directory_scheme);
+
+        for (filepath, maybe_dir) in filepaths.iter().zip(canonical_dirs) {
+            match maybe_dir {
+                Ok(dir) => {
+                    let actual_dir = filepath.parent().unwrap_or(Path::new(""));
+                    if actual_dir != dir.as_path() {
+                        findings.push(
+                            Finding::new(self, filepath, format!("Should be in directory {}", dir.display()))
+                                .with_values(dir.display().to_string(), actual_dir.display().to_string())
+                                .with_fix("run \"organize\""),
+                        );
+                    }
+                }
+                Err(e) => {
+                    findings.push(Finding::new(
+                        self,
+                        filepath,
+                        format!("Error while trying to determine correct directory: {}", e),
+                    ));
+                }
             }
         }
+
+        Ok(findings)
+    }
+}
+
+/// Checks for sidecar files in the collection that do not belong to any photo (e.g., because the photo has been deleted or renamed without
+/// its sidecar files).
+struct OrphanedSidecarsCheck;
+
+impl Check for OrphanedSidecarsCheck {
+    fn name(&self) -> &'static str {
+        "orphaned-sidecars"
     }
 
-    found_misnamed_file
+    fn run(&self, root_dir: &Path, index: &Index, _dry_run: bool) -> Result<Vec<Finding>> {
+        let files = scan_collection_files(&index.user_config, root_dir)
+            .context("Could not scan the collection for sidecar files!")?;
+
+        let (_, orphaned_sidecars) = group_photo_files(files, &index.user_config);
+        Ok(orphaned_sidecars
+            .iter()
+            .map(|sidecar| {
+                Finding::new(self, sidecar, "Sidecar file does not belong to any photo!".into())
+                    .with_fix("rename it to match its photo or delete it")
+            })
+            .collect())
+    }
+}
+
+/// Checks for symbolic links in the collection whose target does not exist (e.g., because the disk containing the target is not mounted).
+struct DanglingSymlinksCheck;
+
+impl Check for DanglingSymlinksCheck {
+    fn name(&self) -> &'static str {
+        "dangling-symlinks"
+    }
+
+    fn run(&self, root_dir: &Path, index: &Index, _dry_run: bool) -> Result<Vec<Finding>> {
+        let dangling_symlinks = find_dangling_symlinks(&index.user_config, root_dir)
+            .context("Could not scan the collection for symbolic links!")?;
+
+        Ok(dangling_symlinks
+            .iter()
+            .map(|link| {
+                Finding::new(self, link, "Target of symbolic link does not exist!".into())
+                    .with_fix("mount the disk containing the target or remove the link")
+            })
+            .collect())
+    }
+}
+
+/// Checks whether all photos in the index can be decoded (see verify_photos_decodable()), detecting files that have been truncated or
+/// corrupted before they were indexed. Since decoding is expensive, the hashes of files that have been verified successfully are cached, so
+/// that only new and modified files are decoded again. Nevertheless, decoding the whole collection is expensive, so the check has to be
+/// enabled explicitly by configuring its severity.
+struct DecodabilityCheck;
+
+/// Hashes of the files that have been verified to be decodable.
+#[derive(Deserialize, Serialize)]
+struct DecodabilityCache {
+    verified_hashes: BTreeSet<String>,
+}
+
+impl Check for DecodabilityCheck {
+    fn name(&self) -> &'static str {
+        "decodability"
+    }
+
+    fn default_severity(&self) -> Severity {
+        Severity::Ignore
+    }
+
+    fn run(&self, root_dir: &Path, index: &Index, dry_run: bool) -> Result<Vec<Finding>> {
+        let mut verified_hashes =
+            read_cache_file::<DecodabilityCache>(root_dir, DECODABILITY_CACHE_FILE_NAME, DECODABILITY_CACHE_VERSION)
+                .map(|c| c.verified_hashes)
+                .unwrap_or_default();
+        let unverified_entries: Vec<&IndexEntry> = index
+            .photos
+            .iter()
+            .filter(|e| !verified_hashes.contains(&e.filehash))
+            .collect();
+        debug!(
+            "{} of {} files have already been verified to be decodable.",
+            index.photos.len() - unverified_entries.len(),
+            index.photos.len()
+        );
+
+        let filepaths: Vec<PathBuf> = unverified_entries.iter().map(|e| e.filepath.clone()).collect();
+        let results = verify_photos_decodable(root_dir, &filepaths);
+
+        let mut findings = vec![];
+        let mut unsupported_count = 0;
+        for (entry, res) in unverified_entries.into_iter().zip(results) {
+            match res {
+                Ok(Decodability::Decodable) => {
+                    verified_hashes.insert(entry.filehash.clone());
+                }
+                Ok(Decodability::Unsupported(reason)) => {
+                    debug!("{}: Cannot verify file - {}", entry.filepath.display(), reason);
+                    unsupported_count += 1;
+                }
+                Err(e) => findings.push(
+                    Finding::new(self, &entry.filepath, format!("File seems to be corrupt: {:#}", e))
+                        .with_fix("restore the file from a backup"),
+                ),
+            }
+        }
+
+        if unsupported_count > 0 {
+            warn!(
+                "{} files could not be verified to be decodable since their format is not supported (run with --verbose to show them).",
+                unsupported_count
+            );
+        }
+
+        // Only keep the hashes of indexed files to prevent the cache from growing indefinitely
+        let indexed_hashes: HashSet<&String> = index.photos.iter().map(|e| &e.filehash).collect();
+        verified_hashes.retain(|h| indexed_hashes.contains(h));
+        if dry_run {
+            debug!("Decodability cache not written (running in dry-run mode).");
+        } else if let Err(e) = write_cache_file(
+            root_dir,
+            DECODABILITY_CACHE_FILE_NAME,
+            DECODABILITY_CACHE_VERSION,
+            DecodabilityCache { verified_hashes },
+        ) {
+            warn!("Could not write decodability cache: {:#}", e);
+        }
+
+        Ok(findings)
+    }
+}
+
+/// Groups the files in the index into photos (see group_photo_files()), ignoring indexed sidecar files whose photo is missing.
+fn get_indexed_photos(index: &Index) -> Vec<Photo> {
+    let filepaths = index.photos.iter().map(|p| p.filepath.clone()).collect();
+    group_photo_files(filepaths, &index.user_config).0
 }
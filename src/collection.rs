@@ -3,39 +3,138 @@ use chrono::prelude::*;
 use hex::encode;
 use image::GenericImageView;
 use log::{debug, warn};
+use rayon::prelude::*;
+use serde::{Serialize, Serializer};
 use sha2::{Digest, Sha256};
-use std::fs::File;
-use std::io::{copy, BufReader, Cursor};
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fmt::{self, Write};
+use std::fs::{self, metadata, File};
+use std::io::{BufReader, Cursor, Read, Seek};
+use std::path::{Component, Path, PathBuf};
 use std::str::from_utf8;
+use std::time::Instant;
 use walkdir::WalkDir;
 
-use crate::index::UserConfig;
+use crate::bmff::{is_bmff, read_top_level_box_types};
+use crate::heif::{is_heif, read_heif_exif, read_heif_jpeg_image};
+use crate::ignore::IgnoreRules;
+use crate::index::{parse_utc_offset, Index, IndexEntry, TimeZonePolicy, UserConfig};
+use crate::jpeg::{has_end_of_image_marker, is_dct_coded};
+use crate::naming::{NamingScheme, NamingValues};
+use crate::progress::{with_progress, Progress};
+use crate::raf::{is_raf, read_raf_jpeg_image};
+use crate::tiff::{find_embedded_jpegs, Tiff};
+use crate::video::read_video_metadata;
 
 #[derive(Clone)]
 pub struct Photo {
     pub relative_path: PathBuf,
+
+    /// Files that belong to the photo and are moved and renamed together with it (paths relative to the root directory): Files of a
+    /// companion file type with the same filename stem (e.g., a RAW file stored alongside the JPEG file) and sidecar files
+    pub companions: Vec<PathBuf>,
 }
 
 /// Holds photo meta data that are extracted from the EXIF data. This struct contains only the subset of the EXIF data that is used within
 /// this project right now.
+#[derive(Serialize)]
 pub struct PhotoMetaData {
     pub make: Option<String>,
     pub model: Option<String>,
-    pub timestamp_local: Option<NaiveDateTime>,
+    pub timestamp: Option<PhotoTimestamp>,
     pub location: Option<(f64, f64)>,
     pub altitude: Option<f64>,
     pub orientation: Option<u16>,
+
+    /// Width and height in pixels
+    pub dimensions: Option<(u32, u32)>,
+
+    /// Duration in seconds (for videos)
+    pub duration: Option<f64>,
+}
+
+/// Timestamp of a photo as recorded by the camera (i.e., in the local time the camera clock was set to), together with its UTC offset if
+/// that is known.
+#[derive(Clone, Copy)]
+pub struct PhotoTimestamp {
+    pub local: NaiveDateTime,
+    pub offset: Option<FixedOffset>,
+}
+
+impl PhotoTimestamp {
+    /// Returns the timestamp in UTC, if the UTC offset is known.
+    pub fn to_utc(self) -> Option<DateTime<Utc>> {
+        self.offset
+            .and_then(|offset| offset.from_local_datetime(&self.local).single())
+            .map(|ts| ts.with_timezone(&Utc))
+    }
+
+    /// Formats the timestamp in the time zone given by the policy, using the given chrono format string. Returns an error if the
+    /// timestamp needs to be converted to another time zone but its UTC offset is unknown, or if the format string is invalid.
+    pub fn format(&self, policy: TimeZonePolicy, fmt: &str) -> Result<String> {
+        let offset = match (policy, self.offset) {
+            (TimeZonePolicy::Local, offset) => offset,
+            (TimeZonePolicy::Utc, Some(_)) => Some(FixedOffset::east_opt(0).unwrap()),
+            (TimeZonePolicy::Fixed(fixed), Some(_)) => Some(fixed),
+            (_, None) => bail!("UTC offset of timestamp unknown, cannot convert it to the configured time zone."),
+        };
+
+        // Note: Writing to a string (instead of using to_string()) returns an error instead of panicking for invalid format strings
+        let mut res = String::new();
+        let written = match (offset, self.offset) {
+            (Some(target_offset), Some(own_offset)) => {
+                let ts = own_offset
+                    .from_local_datetime(&self.local)
+                    .single()
+                    .context("Invalid local timestamp!")?
+                    .with_timezone(&target_offset);
+                write!(res, "{}", ts.format(fmt))
+            }
+            _ => write!(res, "{}", self.local.format(fmt)),
+        };
+        written.map_err(|_| anyhow!("Invalid format string \"{}\" for timestamp.", fmt))?;
+
+        Ok(res)
+    }
+}
+
+/// Serializes the timestamp in the RFC 3339 format (without UTC offset if that is unknown).
+impl Serialize for PhotoTimestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self
+            .offset
+            .and_then(|offset| offset.from_local_datetime(&self.local).single())
+        {
+            Some(ts) => serializer.serialize_str(&ts.to_rfc3339()),
+            None => serializer.collect_str(&self.local.format("%Y-%m-%dT%H:%M:%S")),
+        }
+    }
+}
+
+impl fmt::Display for PhotoTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.local.format("%d.%m.%Y %H:%M:%S"))?;
+        if let Some(offset) = self.offset {
+            write!(f, " {}", offset)?;
+        }
+        Ok(())
+    }
 }
 
 impl Photo {
+    /// Returns the paths of all files of the photo, i.e., of the photo itself followed by its companions.
+    pub fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.relative_path).chain(self.companions.iter())
+    }
+
     /// Returns a JPEG representation of the image scaled down to the given maximum width.
     pub fn get_thumbnail(&self, root_dir: &Path, max_width: u32) -> Result<Vec<u8>> {
         let path: PathBuf = root_dir.join(&self.relative_path);
 
         // Read image and EXIF tags for orientation (see below)
         let mut img: image::DynamicImage =
-            image::open(&path).with_context(|| format!("Could not read image from {}!", path.display()))?;
+            read_image(&path).with_context(|| format!("Could not read image from {}!", path.display()))?;
         match read_exif_data(&path) {
             Ok(exif_data) => {
                 debug!(
@@ -50,36 +149,7 @@ impl Photo {
                 //       https://github.com/image-rs/image/issues/1045). Hence, we are manually flipping/rotation the read image here to get an
                 //       unrotated representation before generating the thumbnail.
                 if let Some(orientation) = exif_data.orientation {
-                    match orientation {
-                        1 => { /* orientation already correct */ }
-                        2 => {
-                            img = img.fliph();
-                        }
-                        3 => {
-                            img = img.rotate180();
-                        }
-                        4 => {
-                            img = img.flipv();
-                        }
-                        5 => {
-                            img = img.rotate90().fliph();
-                        }
-                        6 => {
-                            img = img.rotate90();
-                        }
-                        7 => {
-                            img = img.rotate270().fliph();
-                        }
-                        8 => {
-                            img = img.rotate270();
-                        }
-                        _ => {
-                            warn!(
-                                "Invalid EXIF rotation value {} ignored! (valid values are 1 to 8)",
-                                orientation
-                            );
-                        }
-                    }
+                    img = apply_orientation(img, orientation);
                 }
 
                 // Resize image to given width
@@ -102,60 +172,358 @@ impl Photo {
         Ok(bytes)
     }
 
+    /// Returns a perceptual hash of the image (a difference hash of its upright orientation), which differs in only a few bits for visually
+    /// similar images like resized or re-encoded copies and edited exports of a photo, as opposed to the file hash.
+    pub fn get_perceptual_hash(&self, root_dir: &Path) -> Result<u64> {
+        let path: PathBuf = root_dir.join(&self.relative_path);
+
+        // Skip videos (ISO base media files that are not HEIF images, see read_media_metadata()) instead of reading them completely
+        let mut header = [0; 12];
+        let header_length = File::open(&path).and_then(|mut f| f.read(&mut header))?;
+        if is_bmff(&header[..header_length]) && !is_heif(&header[..header_length]) {
+            bail!("Perceptual hashes of videos are not supported");
+        }
+
+        let mut img: image::DynamicImage =
+            read_image(&path).with_context(|| format!("Could not read image from {}!", path.display()))?;
+        if let Some(orientation) = read_exif_data(&path).ok().and_then(|exif_data| exif_data.orientation) {
+            img = apply_orientation(img, orientation);
+        }
+
+        // Compare the brightness of horizontally adjacent pixels of the image scaled down to 9x8 pixels, yielding 64 bits
+        let small = img.thumbnail_exact(9, 8).to_luma8();
+        let mut hash = 0u64;
+        for y in 0..8 {
+            for x in 0..8 {
+                hash = (hash << 1) | u64::from(small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0]);
+            }
+        }
+
+        Ok(hash)
+    }
+
     // TODO: Move remaining functions (below) here
 }
 
 /// Hashes the given file and returns the hash as a hex-encoded string.
-pub fn calc_photo_hash(filepath: &PathBuf) -> Result<String> {
+pub fn calc_photo_hash(filepath: &Path) -> Result<String> {
+    hash_file(filepath, None)
+}
+
+/// Hashes the given data (e.g., the new contents of a file before writing it) and returns the hash as a hex-encoded string.
+pub fn calc_data_hash(data: &[u8]) -> String {
+    encode(Sha256::digest(data))
+}
+
+/// Hashes the given files (relative to the root directory) in parallel while reporting the progress on stderr. The results are returned
+/// in the order of the given paths, so that any output generated from them stays deterministic.
+pub fn calc_photo_hashes(root_dir: &Path, filepaths: &[PathBuf]) -> Vec<Result<String>> {
+    let total_bytes = filepaths
+        .par_iter()
+        .map(|p| metadata(root_dir.join(p)).map(|md| md.len()).unwrap_or(0))
+        .sum();
+
+    with_progress("Hashing", filepaths.len() as u64, total_bytes, |progress| {
+        filepaths
+            .par_iter()
+            .map(|p| {
+                let res = hash_file(&root_dir.join(p), Some(progress));
+                progress.finish_file();
+                res
+            })
+            .collect()
+    })
+}
+
+/// Hashes the given files like calc_photo_hashes(), but does not start hashing any further file once the given deadline has passed. The
+/// results of files that have not been hashed are None.
+pub fn calc_photo_hashes_until(
+    root_dir: &Path,
+    filepaths: &[PathBuf],
+    deadline: Instant,
+) -> Vec<Option<Result<String>>> {
+    let total_bytes = filepaths
+        .par_iter()
+        .map(|p| metadata(root_dir.join(p)).map(|md| md.len()).unwrap_or(0))
+        .sum();
+
+    with_progress("Hashing", filepaths.len() as u64, total_bytes, |progress| {
+        filepaths
+            .par_iter()
+            .map(|p| {
+                if Instant::now() >= deadline {
+                    return None;
+                }
+
+                let res = hash_file(&root_dir.join(p), Some(progress));
+                progress.finish_file();
+                Some(res)
+            })
+            .collect()
+    })
+}
+
+/// Hashes the given file, optionally recording the number of bytes read in the given progress tracker.
+fn hash_file(filepath: &Path, progress: Option<&Progress>) -> Result<String> {
     let mut file =
         File::open(filepath).with_context(|| format!("Could not open {} for hashing!", filepath.display()))?;
     let mut hasher = Sha256::new();
-    copy(&mut file, &mut hasher)?;
-    let hash = hasher.finalize();
-    Ok(encode(hash))
-}
-
-/// Determines the "correct" filename for a given photo, using the provided user config with its file naming scheme.
-pub fn get_canonical_photo_filename(filepath: &PathBuf, user_config: &UserConfig) -> Result<String> {
-    let exif_data = read_exif_data(filepath)?;
-
-    match exif_data.timestamp_local {
-        Some(timestamp_local) => {
-            match filepath.extension() {
-                Some(file_extension) => {
-                    let file_extension = file_extension.to_string_lossy().to_lowercase();
-
-                    // Replace file type identifier in the name
-                    let (file_type_name, _) = user_config
-                        .file_types
-                        .iter()
-                        .find(|(_, allowed_file_extensions)| allowed_file_extensions.contains(&file_extension))
-                        .ok_or_else(|| anyhow!("File extension not defined in configuration."))?;
-                    let cur_name = user_config.file_naming_scheme.replace("%{type}", file_type_name);
-
-                    // Replace file extension in template: Lower case existing file extension (which a hardcoded special rule to rewrite
-                    // "jpeg" to "jpg" though)
-                    let cur_name = cur_name.replace(
-                        "%{fileextension}",
-                        if file_extension == "jpeg" {
-                            "jpg"
-                        } else {
-                            &file_extension
-                        },
-                    );
-
-                    // Replace datetime fields with timestamp
-                    Ok(timestamp_local.format(&cur_name).to_string())
-                }
-                None => {
-                    bail!("Could not determine file extension.")
-                }
+    let mut buf = vec![0; 1 << 20];
+
+    loop {
+        let n = file
+            .read(&mut buf)
+            .with_context(|| format!("Could not read {} for hashing!", filepath.display()))?;
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..n]);
+        if let Some(progress) = progress {
+            progress.add_bytes(n as u64);
+        }
+    }
+
+    Ok(encode(hasher.finalize()))
+}
+
+/// Reads size and modification time of the given file. These are recorded in the index to detect changed files without re-hashing them.
+pub fn get_file_metadata(filepath: &Path) -> Result<(u64, DateTime<Utc>)> {
+    let md = metadata(filepath).with_context(|| format!("Could not read file metadata of {}!", filepath.display()))?;
+    let modification_time = md
+        .modified()
+        .with_context(|| format!("Could not determine modification time of {}!", filepath.display()))?;
+    Ok((md.len(), modification_time.into()))
+}
+
+/// Determines the "correct" filename for a given photo with the given EXIF data, using the given file naming scheme (parsed from the
+/// provided user config). The original filename and hash (as recorded in the index) and the sequence number of the photo within its
+/// directory are used for the corresponding tokens of the naming scheme. The returned filename is not yet disambiguated from other photos
+/// (see get_canonical_photo_filenames()).
+pub fn get_canonical_photo_filename(
+    filepath: &Path,
+    exif_data: &PhotoMetaData,
+    scheme: &NamingScheme,
+    user_config: &UserConfig,
+    orig_filename: &str,
+    filehash: Option<&str>,
+    seq: Option<usize>,
+) -> Result<String> {
+    let timestamp = exif_data.timestamp.context("EXIF timestamp not set.")?;
+    let file_extension = filepath
+        .extension()
+        .context("Could not determine file extension.")?
+        .to_string_lossy()
+        .to_lowercase();
+
+    // Determine file type identifier
+    let (file_type_name, _) = user_config
+        .file_types
+        .iter()
+        .find(|(_, allowed_file_extensions)| allowed_file_extensions.contains(&file_extension))
+        .ok_or_else(|| anyhow!("File extension not defined in configuration."))?;
+
+    // Replace tokens in template: Lower case existing file extension (which a hardcoded special rule to rewrite "jpeg" to "jpg" though)
+    let cur_name = scheme.format_string(&NamingValues {
+        file_type: file_type_name,
+        file_extension: if file_extension == "jpeg" {
+            "jpg"
+        } else {
+            &file_extension
+        },
+        make: exif_data.make.as_deref(),
+        model: exif_data.model.as_deref(),
+        orig_filename,
+        filehash,
+        seq,
+    })?;
+
+    // Replace datetime fields with timestamp (in the configured time zone)
+    timestamp.format(user_config.file_naming_time_zone, &cur_name)
+}
+
+/// Determines the directory (relative to the root directory) for a photo with the given timestamp using the given directory scheme (chrono
+/// format string), expressing the timestamp in the time zone configured for the file naming.
+pub fn format_photo_directory(
+    timestamp: &PhotoTimestamp,
+    directory_scheme: &str,
+    user_config: &UserConfig,
+) -> Result<PathBuf> {
+    let directory = PathBuf::from(timestamp.format(user_config.file_naming_time_zone, directory_scheme)?);
+
+    if !directory.components().all(|c| matches!(c, Component::Normal(_))) {
+        bail!(
+            "Directory scheme \"{}\" does not result in a relative path within the collection (got {}).",
+            directory_scheme,
+            directory.display()
+        );
+    }
+
+    Ok(directory)
+}
+
+/// Determines the "correct" directories (relative to the root directory) for the given photos (paths relative to the root directory) using
+/// the given directory scheme. The results are returned in the order of the given paths.
+pub fn get_canonical_photo_directories(
+    root_dir: &Path,
+    filepaths: &[PathBuf],
+    index: &Index,
+    directory_scheme: &str,
+) -> Vec<Result<PathBuf>> {
+    let index_map: HashMap<&Path, &IndexEntry> = index.photos.iter().map(|p| (p.filepath.as_path(), p)).collect();
+
+    filepaths
+        .par_iter()
+        .map(|filepath| {
+            let filehash = index_map.get(filepath.as_path()).map(|e| e.filehash.as_str());
+            let exif_data = read_photo_metadata(root_dir, filepath, filehash, &index.user_config)?;
+            let timestamp = exif_data.timestamp.context("EXIF timestamp not set.")?;
+            format_photo_directory(&timestamp, directory_scheme, &index.user_config)
+        })
+        .collect()
+}
+
+/// Inserts the given collision suffix (with %{counter} replaced by the given counter) into the given filename before the file extension.
+pub fn add_collision_suffix(name: &str, collision_suffix: &str, counter: usize) -> String {
+    let suffix = collision_suffix.replace("%{counter}", &counter.to_string());
+    match name.rfind('.') {
+        Some(pos) => format!("{}{}{}", &name[..pos], suffix, &name[pos..]),
+        None => format!("{}{}", name, suffix),
+    }
+}
+
+/// Determines the "correct" filenames for the given photos (paths relative to the root directory) using the file naming scheme configured
+/// in the index. Photos in the same directory that would get the same filename (e.g., burst shots taken within the same second) are
+/// disambiguated by inserting the configured collision suffix before the file extension, numbering them by their timestamp (including
+/// sub-seconds), then by their original filename and hash recorded in the index. The sequence numbers of the photos within their directory
+/// (for the %{seq} token) are determined in the same order. Hence, the assigned names do not depend on the current filenames and renaming
+/// is idempotent. Note that all photos of a directory have to be passed for the disambiguation and numbering to work properly. The results
+/// are returned in the order of the given paths.
+pub fn get_canonical_photo_filenames(root_dir: &Path, filepaths: &[PathBuf], index: &Index) -> Vec<Result<String>> {
+    get_canonical_photo_filenames_with_collisions(root_dir, filepaths, index).0
+}
+
+/// Like get_canonical_photo_filenames(), but additionally returns the collision groups, i.e., the indices of the photos that would get the
+/// same filename without the collision suffix (only groups of at least two photos).
+pub fn get_canonical_photo_filenames_with_collisions(
+    root_dir: &Path,
+    filepaths: &[PathBuf],
+    index: &Index,
+) -> (Vec<Result<String>>, Vec<Vec<usize>>) {
+    let index_map: HashMap<&Path, &IndexEntry> = index.photos.iter().map(|p| (p.filepath.as_path(), p)).collect();
+
+    let metadata: Vec<Result<PhotoMetaData>> = filepaths
+        .par_iter()
+        .map(|filepath| {
+            let filehash = index_map.get(filepath.as_path()).map(|e| e.filehash.as_str());
+            read_photo_metadata(root_dir, filepath, filehash, &index.user_config)
+        })
+        .collect();
+    let timestamps: Vec<Option<PhotoTimestamp>> = metadata
+        .iter()
+        .map(|m| m.as_ref().ok().and_then(|m| m.timestamp))
+        .collect();
+
+    // Original filename and hash recorded in the index (falling back to the current filename for photos that are not indexed yet)
+    let index_info: Vec<(String, Option<&str>)> = filepaths
+        .iter()
+        .map(|filepath| match index_map.get(filepath.as_path()) {
+            Some(entry) => (entry.orig_filename.clone(), Some(entry.filehash.as_str())),
+            None => (filepath.file_name().unwrap_or_default().to_string_lossy().into(), None),
+        })
+        .collect();
+
+    // Order photos by properties that do not change when renaming them
+    let sort_key = |i: &usize| {
+        (
+            timestamps[*i].and_then(|ts| ts.to_utc()),
+            timestamps[*i].map(|ts| ts.local),
+            index_info[*i].0.clone(),
+            index_info[*i].1.unwrap_or_default().to_string(),
+            filepaths[*i].clone(),
+        )
+    };
+
+    // Number photos with timestamp within their directory (if needed by the naming scheme)
+    let mut seqs: Vec<Option<usize>> = vec![None; filepaths.len()];
+    let scheme = NamingScheme::parse(&index.user_config.file_naming_scheme);
+    let uses_seq = scheme.as_ref().map(|s| s.uses_seq()).unwrap_or(false);
+    if uses_seq {
+        let mut dirs: HashMap<&Path, Vec<usize>> = HashMap::new();
+        for (i, filepath) in filepaths.iter().enumerate().filter(|(i, _)| timestamps[*i].is_some()) {
+            dirs.entry(filepath.parent().unwrap_or(Path::new("")))
+                .or_default()
+                .push(i);
+        }
+
+        for (_, mut photos) in dirs {
+            photos.sort_by_cached_key(sort_key);
+            for (seq, i) in photos.into_iter().enumerate() {
+                seqs[i] = Some(seq + 1);
             }
         }
-        None => {
-            bail!("EXIF timestamp not set.");
+    }
+
+    // Determine undisambiguated names
+    let mut names: Vec<Result<String>> = metadata
+        .into_par_iter()
+        .enumerate()
+        .map(|(i, exif_data)| {
+            let scheme = scheme
+                .as_ref()
+                .map_err(|e| anyhow!("Invalid file naming scheme: {}", e))?;
+            get_canonical_photo_filename(
+                &filepaths[i],
+                &exif_data?,
+                scheme,
+                &index.user_config,
+                &index_info[i].0,
+                index_info[i].1,
+                seqs[i],
+            )
+        })
+        .collect();
+
+    // Group photos by directory and name to find collisions
+    let mut groups: HashMap<(&Path, &str), Vec<usize>> = HashMap::new();
+    for (i, (filepath, name)) in filepaths.iter().zip(names.iter()).enumerate() {
+        if let Ok(name) = name {
+            let dir = filepath.parent().unwrap_or(Path::new(""));
+            groups.entry((dir, name.as_str())).or_default().push(i);
+        }
+    }
+
+    let mut suffixed_names: Vec<(usize, String)> = vec![];
+    let mut failed_photos: Vec<usize> = vec![];
+    let mut collisions: Vec<Vec<usize>> = vec![];
+
+    for ((_, name), mut photos) in groups.into_iter().filter(|(_, photos)| photos.len() > 1) {
+        collisions.push(photos.clone());
+        if !index.user_config.file_naming_collision_suffix.contains("%{counter}") {
+            failed_photos.extend(photos);
+            continue;
+        }
+
+        photos.sort_by_cached_key(sort_key);
+
+        for (counter, i) in photos.into_iter().enumerate() {
+            let suffixed_name =
+                add_collision_suffix(name, &index.user_config.file_naming_collision_suffix, counter + 1);
+            suffixed_names.push((i, suffixed_name));
         }
     }
+
+    for (i, suffixed_name) in suffixed_names {
+        names[i] = Ok(suffixed_name);
+    }
+
+    for i in failed_photos {
+        names[i] = Err(anyhow!(
+            "Canonical filename collides with another photo, but the collision suffix does not contain %{{counter}}."
+        ));
+    }
+
+    (names, collisions)
 }
 
 /// Get all photos that are in a specific subdirectory (and possibly its subdirectories).
@@ -173,23 +541,293 @@ pub fn get_photos_in_subdir(photos: &[Photo], subdir: &Path, recursive: bool) ->
         .collect()
 }
 
-pub fn read_exif_data(filepath: &PathBuf) -> Result<PhotoMetaData> {
-    let file = File::open(filepath)
+/// Reads the meta data of the photo at the given path (relative to the root directory) and applies the camera clock corrections configured
+/// in the user config to its timestamp. The hash of the photo recorded in the index (None if the photo is not indexed) is used to find the
+/// corrections tied to the photo.
+pub fn read_photo_metadata(
+    root_dir: &Path,
+    relative_path: &Path,
+    filehash: Option<&str>,
+    user_config: &UserConfig,
+) -> Result<PhotoMetaData> {
+    let mut pmd = read_media_metadata(&root_dir.join(relative_path))?;
+    apply_time_corrections(&mut pmd, filehash, user_config);
+    Ok(pmd)
+}
+
+/// Reads the meta data of a photo outside of the collection (e.g., on a memory card that is imported) and applies the camera-specific
+/// clock corrections configured in the user config to its timestamp.
+pub fn read_external_photo_metadata(filepath: &Path, user_config: &UserConfig) -> Result<PhotoMetaData> {
+    let mut pmd = read_media_metadata(filepath)?;
+    apply_time_corrections(&mut pmd, None, user_config);
+    Ok(pmd)
+}
+
+/// Adds the offsets of all camera clock corrections matching the given photo to its timestamp.
+fn apply_time_corrections(pmd: &mut PhotoMetaData, filehash: Option<&str>, user_config: &UserConfig) {
+    let offset_seconds: i64 = user_config
+        .time_corrections
+        .iter()
+        .filter(|c| c.matches(filehash, pmd.make.as_deref(), pmd.model.as_deref()))
+        .map(|c| c.offset_seconds)
+        .sum();
+
+    if offset_seconds != 0 {
+        if let Some(ts) = pmd.timestamp.as_mut() {
+            ts.local += chrono::Duration::seconds(offset_seconds);
+        }
+    }
+}
+
+/// Reads the meta data of the given file, which is taken from the EXIF data for images and from the movie header for videos (ISO base media
+/// files like MP4 and MOV that are not HEIF images, determined by the file contents).
+pub fn read_media_metadata(filepath: &Path) -> Result<PhotoMetaData> {
+    let mut header = [0; 12];
+    let header_length = File::open(filepath)
+        .and_then(|mut f| f.read(&mut header))
+        .with_context(|| format!("Could not open {} for reading meta data!", filepath.display()))?;
+
+    if is_bmff(&header[..header_length]) && !is_heif(&header[..header_length]) {
+        read_video_metadata(filepath)
+    } else {
+        read_exif_data(filepath)
+    }
+}
+
+/// Reads the EXIF data of the given file. Besides the containers supported by the exif crate (e.g., JPEG, PNG and TIFF, which includes
+/// TIFF-based RAW formats like DNG, CR2, NEF and ARW), HEIF files, the TIFF variants used by Olympus (ORF) and Panasonic (RW2) RAW files,
+/// RAW files that store the EXIF data in their embedded JPEG preview only (e.g., RW2) and Fujifilm RAF files (whose EXIF data is read from
+/// the JPEG preview the RAF header points to) are supported.
+fn read_exif(filepath: &Path) -> Result<exif::Exif> {
+    let mut file = File::open(filepath)
         .with_context(|| format!("Could not open {} for reading EXIF data!", filepath.display()))?;
-    let mut bufreader = BufReader::new(&file);
-    let exifreader = exif::Reader::new();
-    let exif = exifreader
-        .read_from_container(&mut bufreader)
-        .with_context(|| format!("Could not read EXIF data from {}!", filepath.display()))?;
+    let mut header = [0; 16];
+    let header_length = file.read(&mut header)?;
+
+    if is_heif(&header[..header_length]) {
+        let data = fs::read(filepath)?;
+        return Ok(exif::Reader::new().read_raw(read_heif_exif(&data)?)?);
+    }
+
+    if is_raf(&header[..header_length]) {
+        let data = fs::read(filepath)?;
+        return Ok(exif::Reader::new().read_from_container(&mut Cursor::new(read_raf_jpeg_image(&data)?))?);
+    }
+
+    let Ok(tiff) = Tiff::new(&header[..header_length]) else {
+        file.rewind()?;
+        return Ok(exif::Reader::new().read_from_container(&mut BufReader::new(&file))?);
+    };
+
+    let mut data = vec![];
+    file.rewind()?;
+    file.read_to_end(&mut data)?;
+
+    // Replace the magic number of TIFF variants with the standard one, which is the only one accepted by the exif crate
+    if !tiff.has_standard_magic() {
+        let magic: [u8; 2] = if tiff.little_endian() { [42, 0] } else { [0, 42] };
+        data[2..4].copy_from_slice(&magic);
+    }
+
+    let exif = exif::Reader::new().read_raw(data)?;
+    if exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY).is_none() {
+        for (offset, length) in find_embedded_jpegs(exif.buf()).unwrap_or_default() {
+            let preview_exif =
+                exif::Reader::new().read_from_container(&mut Cursor::new(&exif.buf()[offset..offset + length]));
+            if let Ok(preview_exif) = preview_exif {
+                if preview_exif
+                    .get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)
+                    .is_some()
+                {
+                    return Ok(preview_exif);
+                }
+            }
+        }
+    }
+
+    Ok(exif)
+}
+
+/// Returns the given image rotated and/or flipped according to the given EXIF orientation value, so that it is shown upright.
+fn apply_orientation(img: image::DynamicImage, orientation: u16) -> image::DynamicImage {
+    match orientation {
+        1 => img,
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => {
+            warn!(
+                "Invalid EXIF rotation value {} ignored! (valid values are 1 to 8)",
+                orientation
+            );
+            img
+        }
+    }
+}
+
+/// Reads the image in the given file. For files whose format is not supported by the image crate, an embedded JPEG image is read instead:
+/// The largest JPEG-coded item of HEIF files (usually a thumbnail), the JPEG preview of RAF files or the largest embedded JPEG preview of
+/// TIFF-based RAW files that can be decoded.
+fn read_image(filepath: &Path) -> Result<image::DynamicImage> {
+    if image::ImageFormat::from_path(filepath).is_ok() {
+        return Ok(image::open(filepath)?);
+    }
+
+    let data = fs::read(filepath)?;
+    if is_heif(&data) {
+        let jpeg = read_heif_jpeg_image(&data)?;
+        return Ok(image::load_from_memory_with_format(&jpeg, image::ImageFormat::Jpeg)?);
+    }
+
+    if is_raf(&data) {
+        let jpeg = read_raf_jpeg_image(&data)?;
+        return Ok(image::load_from_memory_with_format(jpeg, image::ImageFormat::Jpeg)?);
+    }
+
+    for (offset, length) in find_embedded_jpegs(&data)? {
+        match image::load_from_memory_with_format(&data[offset..offset + length], image::ImageFormat::Jpeg) {
+            Ok(img) => return Ok(img),
+            Err(e) => debug!(
+                "{}: Could not decode embedded JPEG image at offset {} - {}",
+                filepath.display(),
+                offset,
+                e
+            ),
+        }
+    }
+
+    bail!("No decodable embedded JPEG preview found");
+}
+
+/// Calculates the perceptual hashes of the given photos in parallel while reporting the progress on stderr (see
+/// Photo::get_perceptual_hash()). Returns the results in the same order as the given photos.
+pub fn calc_perceptual_hashes(root_dir: &Path, photos: &[Photo]) -> Vec<Result<u64>> {
+    let total_bytes = photos
+        .par_iter()
+        .map(|p| {
+            metadata(root_dir.join(&p.relative_path))
+                .map(|md| md.len())
+                .unwrap_or(0)
+        })
+        .sum();
+
+    with_progress("Decoding", photos.len() as u64, total_bytes, |progress| {
+        photos
+            .par_iter()
+            .map(|p| {
+                let res = p.get_perceptual_hash(root_dir);
+                progress.add_bytes(
+                    metadata(root_dir.join(&p.relative_path))
+                        .map(|md| md.len())
+                        .unwrap_or(0),
+                );
+                progress.finish_file();
+                res
+            })
+            .collect()
+    })
+}
+
+/// Result of verifying a file that is not corrupt (see verify_decodable()).
+pub enum Decodability {
+    /// The file has been verified to be decodable
+    Decodable,
+
+    /// The file could not be verified since its format is not supported (with the reason)
+    Unsupported(String),
+}
+
+/// Verifies that the given photos (paths relative to the root directory) are not corrupt (see verify_decodable()). Returns the results in
+/// the same order as the given paths.
+pub fn verify_photos_decodable(root_dir: &Path, filepaths: &[PathBuf]) -> Vec<Result<Decodability>> {
+    let total_bytes = filepaths
+        .par_iter()
+        .map(|p| metadata(root_dir.join(p)).map(|md| md.len()).unwrap_or(0))
+        .sum();
+
+    with_progress("Decoding", filepaths.len() as u64, total_bytes, |progress| {
+        filepaths
+            .par_iter()
+            .map(|p| {
+                let filepath = root_dir.join(p);
+                let res = verify_decodable(&filepath);
+                progress.add_bytes(metadata(&filepath).map(|md| md.len()).unwrap_or(0));
+                progress.finish_file();
+                res
+            })
+            .collect()
+    })
+}
+
+/// Verifies that the given photo or video is not corrupt: Images are fully decoded (RAW files by decoding their largest embedded JPEG
+/// preview that is DCT-coded, mirroring read_image()), while for ISO base media files (videos, HEIF images and CR3 files) the structure of
+/// the top-level boxes is validated. JPEG data also has to be terminated by an end of image marker, since truncated JPEG data can often be
+/// decoded without error. Files of other formats and RAW files without a decodable preview are reported as unsupported.
+fn verify_decodable(filepath: &Path) -> Result<Decodability> {
+    let mut file = File::open(filepath)?;
+    let mut header = vec![];
+    file.by_ref().take(12).read_to_end(&mut header)?;
+
+    if is_bmff(&header) {
+        let box_types = read_top_level_box_types(&mut file)?;
+        let required_box = if is_heif(&header) { b"meta" } else { b"moov" };
+        if !box_types.contains(required_box) {
+            bail!("Box \"{}\" missing!", String::from_utf8_lossy(required_box));
+        }
+        return Ok(Decodability::Decodable);
+    }
+
+    let data = fs::read(filepath)?;
+    if data.starts_with(&[0xff, 0xd8]) {
+        verify_jpeg_decodable(&data)?;
+    } else if image::ImageFormat::from_path(filepath).is_ok() {
+        image::load_from_memory(&data)?;
+    } else if is_raf(&data) {
+        verify_jpeg_decodable(read_raf_jpeg_image(&data)?).context("Embedded JPEG preview is corrupt!")?;
+    } else if Tiff::new(&data).is_ok() {
+        // Note: TIFF-based RAW files whose structure is invalid fail when looking for the previews. Embedded JPEG data that is not
+        //       DCT-coded (e.g., the raw image data stored as lossless JPEG in CR2 and DNG files) is skipped, while JPEG data that cannot
+        //       be parsed at all is verified (and hence reported as corrupt).
+        let preview = find_embedded_jpegs(&data)?
+            .into_iter()
+            .find(|(offset, length)| is_dct_coded(&data[*offset..offset + length]).unwrap_or(true));
+        let Some((offset, length)) = preview else {
+            return Ok(Decodability::Unsupported(
+                "No decodable embedded JPEG preview found".into(),
+            ));
+        };
+        verify_jpeg_decodable(&data[offset..offset + length])
+            .with_context(|| format!("Embedded JPEG preview at offset {} is corrupt!", offset))?;
+    } else {
+        return Ok(Decodability::Unsupported("File format is not supported".into()));
+    }
+
+    Ok(Decodability::Decodable)
+}
+
+/// Verifies that the given JPEG data is complete and can be decoded.
+fn verify_jpeg_decodable(data: &[u8]) -> Result<()> {
+    if !has_end_of_image_marker(data)? {
+        bail!("JPEG data is truncated (end of image marker missing)!");
+    }
+    image::load_from_memory_with_format(data, image::ImageFormat::Jpeg)?;
+    Ok(())
+}
+
+pub fn read_exif_data(filepath: &Path) -> Result<PhotoMetaData> {
+    let exif = read_exif(filepath).with_context(|| format!("Could not read EXIF data from {}!", filepath.display()))?;
 
     // Print all EXIF fields for debugging
     /* for f in exif.fields() {
         println!("{} {} {}", f.tag, f.ifd_num, f.display_value().with_unit(&exif));
     } */
 
-    // Note: The timestamp is stored as a string with the format "2022:05:07 12:32:10"
-    // Unfortunately, the standard does not mandate whether this timestamp is stored in local time or in UTC. For now, we are just
-    // assuming it is already in local time, which seems to be true at least for Android mobile phones. Later, this can be revisited.
+    // Note: The timestamp is stored as a string with the format "2022:05:07 12:32:10" in the local time the camera clock was set to. Its
+    // UTC offset is taken from the OffsetTimeOriginal tag (if set) or otherwise derived from the GPS timestamp (see below).
     let timestamp_value = exif
         .get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)
         .map(|e| &e.value);
@@ -198,7 +836,35 @@ pub fn read_exif_data(filepath: &PathBuf) -> Result<PhotoMetaData> {
         let s = from_utf8(s).context("Could not parse EXIF DateTime value as utf8!")?;
         let ts = NaiveDateTime::parse_from_str(s, "%Y:%m:%d %H:%M:%S")
             .with_context(|| format!("Could not parse EXIF DateTime value: {}", s))?;
-        Some(ts)
+
+        // Add sub-seconds (if set), which are stored as the digits of the decimal fraction (e.g., "042" for 42 milliseconds)
+        let subsec_value = exif
+            .get_field(exif::Tag::SubSecTimeOriginal, exif::In::PRIMARY)
+            .map(|e| &e.value);
+        let subsec_nanos = if let Some(exif::Value::Ascii(s)) = subsec_value {
+            s.first()
+                .and_then(|s| from_utf8(s).ok())
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty() && s.len() <= 9 && s.chars().all(|c| c.is_ascii_digit()))
+                .map(|s| s.parse::<u32>().unwrap_or(0) * 10u32.pow(9 - s.len() as u32))
+        } else {
+            None
+        };
+        let ts = ts.with_nanosecond(subsec_nanos.unwrap_or(0)).unwrap_or(ts);
+
+        let offset_value = exif
+            .get_field(exif::Tag::OffsetTimeOriginal, exif::In::PRIMARY)
+            .map(|e| &e.value);
+        let offset = if let Some(exif::Value::Ascii(s)) = offset_value {
+            s.first().and_then(|s| from_utf8(s).ok()).and_then(parse_utc_offset)
+        } else {
+            None
+        };
+
+        Some(PhotoTimestamp {
+            local: ts,
+            offset: offset.or_else(|| derive_utc_offset_from_gps(&exif, &ts)),
+        })
     } else {
         None
     };
@@ -308,40 +974,293 @@ pub fn read_exif_data(filepath: &PathBuf) -> Result<PhotoMetaData> {
         None
     };
 
+    let width = exif
+        .get_field(exif::Tag::PixelXDimension, exif::In::PRIMARY)
+        .and_then(|e| e.value.get_uint(0));
+    let height = exif
+        .get_field(exif::Tag::PixelYDimension, exif::In::PRIMARY)
+        .and_then(|e| e.value.get_uint(0));
+
     Ok(PhotoMetaData {
         model,
         make,
-        timestamp_local: timestamp,
+        timestamp,
         location,
         altitude,
         orientation,
+        dimensions: width.zip(height),
+        duration: None,
     })
 }
 
-/// Recursively walks the given root directory of a photo collection and returns all photos.
+/// Derives the UTC offset of the given local timestamp by comparing it to the GPS timestamp (which is always in UTC) from the EXIF data.
+/// The difference is rounded to 15 minutes since the GPS timestamp usually differs slightly (e.g., it can be taken from the last GPS
+/// fix). Returns None if no GPS timestamp is set or the resulting offset is implausible.
+fn derive_utc_offset_from_gps(exif: &exif::Exif, local: &NaiveDateTime) -> Option<FixedOffset> {
+    let date_value = exif
+        .get_field(exif::Tag::GPSDateStamp, exif::In::PRIMARY)
+        .map(|e| &e.value);
+    let time_value = exif
+        .get_field(exif::Tag::GPSTimeStamp, exif::In::PRIMARY)
+        .map(|e| &e.value);
+
+    let (Some(exif::Value::Ascii(date_vec)), Some(exif::Value::Rational(time_vec))) = (date_value, time_value) else {
+        return None;
+    };
+
+    let date = from_utf8(date_vec.first()?).ok()?;
+    let date = NaiveDate::parse_from_str(date.trim(), "%Y:%m:%d").ok()?;
+    let [hours, minutes, seconds] = time_vec.as_slice() else {
+        return None;
+    };
+    let secs = hours.to_f64() * 3600.0 + minutes.to_f64() * 60.0 + seconds.to_f64();
+    let utc = date.and_hms_opt(0, 0, 0)? + chrono::Duration::milliseconds((secs * 1000.0) as i64);
+
+    let quarter_hours = ((*local - utc).num_seconds() as f64 / 900.0).round() as i32;
+    if quarter_hours.abs() > 14 * 4 {
+        return None;
+    }
+
+    FixedOffset::east_opt(quarter_hours * 900)
+}
+
+/// Recursively walks the given root directory of a photo collection and returns all photos (see group_photo_files()).
 pub fn scan_photo_collection(config: &UserConfig, root_dir: &Path) -> Result<Vec<Photo>> {
-    let filter_file_extensions: Vec<String> = config.file_types.values().flatten().cloned().collect();
-    let mut res = vec![];
+    let (photos, orphaned_sidecars) = group_photo_files(scan_collection_files(config, root_dir)?, config);
+
+    for sidecar in orphaned_sidecars {
+        debug!(
+            "{}: Sidecar file does not belong to any photo, ignoring it",
+            sidecar.display()
+        );
+    }
+
+    Ok(photos)
+}
+
+/// Recursively walks the given root directory of a photo collection and returns the paths (relative to the root directory) of all files
+/// that have one of the configured file types or are sidecar files. Files and directories excluded by the ignore rules (built-in defaults
+/// and ".poignore" files) are skipped, symbolic links are followed and mount points are crossed as configured in the user config.
+pub fn scan_collection_files(config: &UserConfig, root_dir: &Path) -> Result<Vec<PathBuf>> {
+    Ok(walk_collection(config, root_dir)?.0)
+}
+
+/// Recursively walks the given root directory of a photo collection (like scan_collection_files()) and returns the paths (relative to the
+/// root directory) of all symbolic links whose target does not exist.
+pub fn find_dangling_symlinks(config: &UserConfig, root_dir: &Path) -> Result<Vec<PathBuf>> {
+    Ok(walk_collection(config, root_dir)?.1)
+}
+
+/// Walks the directory tree of a photo collection. Returns the files that have one of the configured file types or are sidecar files and
+/// the dangling symbolic links (both relative to the root directory). Symbolic links that would lead to a directory being walked again
+/// (i.e., symlink loops) are skipped with a warning.
+fn walk_collection(config: &UserConfig, root_dir: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let filter_file_extensions: Vec<&String> = config
+        .file_types
+        .values()
+        .flatten()
+        .chain(config.sidecar_extensions.iter())
+        .collect();
+    let ignore_rules = IgnoreRules::new(root_dir);
+    let mut ignore_error = None;
+    let mut files = vec![];
+    let mut dangling_symlinks = vec![];
+
+    let walker = WalkDir::new(root_dir)
+        .follow_links(config.follow_symlinks)
+        .same_file_system(config.same_file_system)
+        .into_iter()
+        .filter_entry(|entry| {
+            let Ok(relative_path) = entry.path().strip_prefix(root_dir) else {
+                return true;
+            };
+            if relative_path.as_os_str().is_empty() {
+                return true;
+            }
+
+            match ignore_rules.is_ignored(relative_path, entry.file_type().is_dir()) {
+                Ok(ignored) => {
+                    if ignored {
+                        debug!("Ignoring {}", relative_path.display());
+                    }
+                    !ignored
+                }
+                Err(e) => {
+                    ignore_error.get_or_insert(e);
+                    false
+                }
+            }
+        });
+
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                // Note: When following symbolic links, dangling links and loops are reported as errors by WalkDir
+                let path = e.path().map(|p| p.strip_prefix(root_dir).unwrap_or(p).to_owned());
+                match path {
+                    Some(path) if e.loop_ancestor().is_some() => {
+                        warn!("{}: Skipping symbolic link that leads to a loop!", path.display());
+                        continue;
+                    }
+                    Some(path) if is_dangling_symlink(&root_dir.join(&path)) => {
+                        dangling_symlinks.push(path);
+                        continue;
+                    }
+                    _ => {
+                        return Err(e).with_context(|| {
+                            format!("Could not traverse directory structure below {}!", root_dir.display())
+                        });
+                    }
+                }
+            }
+        };
+        let path = entry.path();
 
-    for entry in WalkDir::new(root_dir) {
-        let entry =
-            entry.with_context(|| format!("Could not traverse directory structure below {}!", root_dir.display()))?;
+        if entry.path_is_symlink() && !config.follow_symlinks {
+            if is_dangling_symlink(path) {
+                dangling_symlinks.push(path.strip_prefix(root_dir)?.to_owned());
+            } else {
+                debug!("Not following symbolic link {}", path.display());
+            }
+            continue;
+        }
 
         // Check if file has one of the file types that should be considered
         if entry.file_type().is_file() {
-            let path = entry.path();
             if let Some(extension) = path.extension() {
-                if filter_file_extensions.contains(&extension.to_string_lossy().to_lowercase()) {
-                    res.push(Photo {
-                        relative_path: path.strip_prefix(root_dir)?.to_owned(),
-                    });
+                if filter_file_extensions.contains(&&extension.to_string_lossy().to_lowercase()) {
+                    files.push(path.strip_prefix(root_dir)?.to_owned());
                 }
             }
         }
     }
 
+    if let Some(e) = ignore_error {
+        return Err(e);
+    }
+
+    Ok((files, dangling_symlinks))
+}
+
+/// Returns whether the given path is a symbolic link whose target does not exist.
+fn is_dangling_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|m| m.file_type().is_symlink())
+        .unwrap_or(false)
+        && !path.exists()
+}
+
+/// Groups the given files (paths relative to the root directory) into photos: Among the files in the same directory with the same filename
+/// stem, the first file (by path) that does not have a companion file type becomes a photo with all files of a companion file type as its
+/// companions (if there is no such file, the first companion takes its place). All other files become photos of their own. Sidecar files
+/// are attached to the photo of the file whose filename they extend (e.g., "IMG_1234.CR2.xmp") or otherwise to the photo with the same
+/// filename stem (e.g., "IMG_1234.xmp"). Returns the photos sorted by path and the sidecar files that do not belong to any photo.
+pub fn group_photo_files(filepaths: Vec<PathBuf>, config: &UserConfig) -> (Vec<Photo>, Vec<PathBuf>) {
+    let has_extension = |path: &Path, extensions: &[&String]| {
+        path.extension()
+            .map(|e| extensions.contains(&&e.to_string_lossy().to_lowercase()))
+            .unwrap_or(false)
+    };
+    let sidecar_extensions: Vec<&String> = config.sidecar_extensions.iter().collect();
+    let companion_extensions: Vec<&String> = config
+        .companion_file_types
+        .iter()
+        .filter_map(|t| config.file_types.get(t))
+        .flatten()
+        .collect();
+
+    let (mut sidecars, files): (Vec<PathBuf>, Vec<PathBuf>) = filepaths
+        .into_iter()
+        .partition(|p| has_extension(p, &sidecar_extensions));
+
+    // Group files by directory and filename stem
+    let mut groups: BTreeMap<(PathBuf, OsString), Vec<PathBuf>> = BTreeMap::new();
+    for file in files {
+        let key = (
+            file.parent().unwrap_or(Path::new("")).to_owned(),
+            file.file_stem().unwrap_or_default().to_owned(),
+        );
+        groups.entry(key).or_default().push(file);
+    }
+
+    let mut photos: Vec<Photo> = vec![];
+    let mut photo_by_file: HashMap<PathBuf, usize> = HashMap::new();
+    let mut photo_by_stem: HashMap<(PathBuf, OsString), usize> = HashMap::new();
+
+    for (key, mut members) in groups {
+        members.sort_unstable();
+        let (mut companions, mut others): (Vec<PathBuf>, Vec<PathBuf>) = members
+            .into_iter()
+            .partition(|p| has_extension(p, &companion_extensions));
+        if others.is_empty() {
+            others.push(companions.remove(0));
+        }
+
+        photo_by_stem.insert(key, photos.len());
+        for (i, file) in others.into_iter().enumerate() {
+            let companions = if i == 0 {
+                std::mem::take(&mut companions)
+            } else {
+                vec![]
+            };
+            for member in std::iter::once(&file).chain(companions.iter()) {
+                photo_by_file.insert(member.clone(), photos.len());
+            }
+            photos.push(Photo {
+                relative_path: file,
+                companions,
+            });
+        }
+    }
+
+    // Attach sidecar files to their photos
+    let mut orphaned_sidecars = vec![];
+    sidecars.sort_unstable();
+    for sidecar in sidecars {
+        let base = sidecar.with_extension("");
+        let key = (
+            base.parent().unwrap_or(Path::new("")).to_owned(),
+            base.file_name().unwrap_or_default().to_owned(),
+        );
+
+        match photo_by_file.get(&base).or_else(|| photo_by_stem.get(&key)) {
+            Some(i) => photos[*i].companions.push(sidecar),
+            None => orphaned_sidecars.push(sidecar),
+        }
+    }
+
     // Sort photo list by path to declutter the output of various commands that work with this list in its order (e.g., rename and list)
-    res.sort_unstable_by_key(|e| e.relative_path.clone());
+    photos.sort_unstable_by_key(|p| p.relative_path.clone());
+    for photo in photos.iter_mut() {
+        photo.companions.sort_unstable();
+    }
+
+    (photos, orphaned_sidecars)
+}
+
+/// Determines the filename of the given companion file of the given photo (paths relative to the root directory) for the case that the
+/// photo is named with the given filename: The part of the companion filename following the filename stem of the photo (e.g., ".CR2" or
+/// ".CR2.xmp") is appended to the filename stem of the new filename, lower-casing the file extensions (and rewriting "jpeg" to "jpg") like
+/// for the filenames of photos.
+pub fn get_companion_filename(photo_path: &Path, companion_path: &Path, photo_filename: &str) -> String {
+    let photo_stem = photo_path.file_stem().unwrap_or_default().to_string_lossy();
+    let companion_filename = companion_path.file_name().unwrap_or_default().to_string_lossy();
+    let suffix = companion_filename
+        .strip_prefix(photo_stem.as_ref())
+        .unwrap_or(&companion_filename);
+
+    let suffix: Vec<String> = suffix
+        .split('.')
+        .map(|e| match e.to_lowercase().as_str() {
+            "jpeg" => "jpg".into(),
+            e => e.into(),
+        })
+        .collect();
+    let new_stem = match photo_filename.rfind('.') {
+        Some(pos) => &photo_filename[..pos],
+        None => photo_filename,
+    };
 
-    Ok(res)
+    format!("{}{}", new_stem, suffix.join("."))
 }
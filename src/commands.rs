@@ -1,26 +1,329 @@
-use anyhow::{anyhow, Context, Result};
-use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+use anyhow::{bail, Context, Result};
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine as _,
+};
+use chrono::{DateTime, Local, Utc};
 use geo_types::Point;
-use gpx::{write, Gpx, GpxVersion, Waypoint};
+use gpx::{read, write, Gpx, GpxVersion, Waypoint};
 use html_escape::encode_safe;
 use log::{debug, error, info, warn};
 use rayon::prelude::*;
 use regex::Regex;
-use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::{self, read_dir, File};
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Cursor, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-use crate::checks::{check_for_duplicates, check_hashes, check_photo_naming};
-use crate::collection::{calc_photo_hash, get_canonical_photo_filename, get_photos_in_subdir, read_exif_data, Photo};
-use crate::index::{Index, IndexEntry};
+use crate::cache::{read_cache_file, write_cache_file};
+use crate::checks::{Finding, CHECKS};
+use crate::collection::{
+    add_collision_suffix, calc_data_hash, calc_perceptual_hashes, calc_photo_hash, calc_photo_hashes,
+    calc_photo_hashes_until, find_dangling_symlinks, format_photo_directory, get_canonical_photo_directories,
+    get_canonical_photo_filename, get_canonical_photo_filenames_with_collisions, get_companion_filename,
+    get_file_metadata, get_photos_in_subdir, group_photo_files, read_external_photo_metadata, read_photo_metadata,
+    scan_photo_collection, Photo, PhotoMetaData, PhotoTimestamp,
+};
+use crate::ignore::IgnoreRules;
+use crate::index::{
+    parse_utc_offset, write_index_file, CheckConfig, Index, IndexEntry, Severity, TimeCorrection, UserConfig,
+};
+use crate::journal::{read_journal, write_journal, Journal, JournalChange};
+use crate::jpeg::{create_exif_segment, exif_segment_insertion_offset, exif_segment_tiff_data, find_exif_segment};
+use crate::naming::{validate_directory_scheme, NamingScheme};
+use crate::output::{print_records, OutputFormat};
+use crate::progress::format_bytes;
+use crate::tiff::add_gps_location;
 
-/// Runs all checks and returns whether any of the checks has generated a warning.
-pub fn check(root_dir: &Path, index: &Index) -> bool {
-    // Run checks without short-circuit evaluation (i.e., always run all checks)
-    // TODO: Should be configurable later which checks should be run
-    check_for_duplicates(index) | check_hashes(root_dir, index) | check_photo_naming(root_dir, index)
+/// Runs the checks selected by the check configuration and returns their findings (with the configured severity, omitting findings for
+/// files in exempt directories). If only is not empty, just the given checks are run (even if they are set to "ignore", in which case their
+/// findings are reported as warnings). Checks given in skip are never run.
+pub fn check(root_dir: &Path, index: &Index, only: &[String], skip: &[String], dry_run: bool) -> Result<Vec<Finding>> {
+    let mut findings = vec![];
+
+    for check in CHECKS {
+        let name = check.name();
+        let config = index.user_config.checks.get(name).cloned().unwrap_or(CheckConfig {
+            severity: check.default_severity(),
+            exempt_directories: vec![],
+        });
+        let selected = only.is_empty() || only.iter().any(|o| o == name);
+        let severity = match config.severity {
+            _ if !selected || skip.iter().any(|s| s == name) => None,
+            Severity::Ignore if !only.is_empty() => Some(Severity::Warning),
+            Severity::Ignore => None,
+            severity => Some(severity),
+        };
+        let Some(severity) = severity else {
+            debug!("Skipping check {}.", name);
+            continue;
+        };
+
+        debug!("Running check {}...", name);
+        let check_findings = check
+            .run(root_dir, index, dry_run)
+            .with_context(|| format!("Could not run check {}!", name))?;
+
+        findings.extend(
+            check_findings
+                .into_iter()
+                .filter(|f| !config.is_exempt(&f.path))
+                .map(|mut f| {
+                    f.severity = severity;
+                    f
+                }),
+        );
+    }
+
+    Ok(findings)
+}
+
+/// Name of the file (in the root directory) that caches the hashes of the files whose perceptual hash cannot be calculated (e.g., since the
+/// file format is not supported), so that they are not decoded again by every run of the dupes command
+const DUPES_CACHE_FILE_NAME: &str = "photo_organizer_dupes_cache.json";
+
+/// Version of the dupes cache, which has to be incremented whenever more files can be decoded (invalidating the cache)
+const DUPES_CACHE_VERSION: u64 = 1;
+
+/// Files whose perceptual hash cannot be calculated.
+#[derive(Default, Deserialize, Serialize)]
+struct DupesCache {
+    /// Reason why the perceptual hash cannot be calculated by hash of the file
+    unhashable: BTreeMap<String, String>,
+}
+
+/// Photo of a cluster of visually similar photos found by the dupes command.
+#[derive(Serialize)]
+pub struct SimilarPhoto {
+    pub path: PathBuf,
+
+    /// Hamming distance between the perceptual hash of the photo and the one of the first photo of the cluster
+    pub distance: u32,
+}
+
+/// Result of the dupes command.
+#[derive(Default)]
+pub struct DupesReport {
+    /// Clusters of visually similar photos (sorted by path, both the clusters and the photos within a cluster)
+    pub clusters: Vec<Vec<SimilarPhoto>>,
+
+    /// Paths of photos that could not be decoded (e.g., videos) together with the reason
+    pub undecodable: Vec<(PathBuf, String)>,
+
+    /// Whether perceptual hashes have been added to the index
+    pub index_changed: bool,
+}
+
+/// Finds clusters of visually similar photos within the given directory (and its subdirectories in recursive mode) by comparing their
+/// perceptual hashes, which are calculated for photos whose index entry does not have one yet and stored in the index. Two photos are
+/// considered similar if the Hamming distance between their perceptual hashes is at most the given threshold, and clusters are formed such
+/// that all photos of a cluster are similar to each other. The clusters are shown side by side in a HTML file written to the given
+/// directory (unless no cluster is found). Indexed files whose perceptual hash cannot be calculated are recorded in a cache (unless running
+/// in dry-run mode), so that they are not decoded again by the next run.
+#[allow(clippy::too_many_arguments)]
+pub fn dupes(
+    root_dir: &Path,
+    subdir: &Path,
+    index: &mut Index,
+    photos: &[Photo],
+    threshold: u32,
+    output_filename: &str,
+    recursive: bool,
+    resize_width: u32,
+    dry_run: bool,
+) -> Result<DupesReport> {
+    let photos = get_photos_in_subdir(photos, subdir, recursive);
+    let entry_indices: HashMap<PathBuf, usize> = index
+        .photos
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| (entry.filepath.clone(), i))
+        .collect();
+    let mut report = DupesReport::default();
+    let mut cache: DupesCache =
+        read_cache_file(root_dir, DUPES_CACHE_FILE_NAME, DUPES_CACHE_VERSION).unwrap_or_default();
+    let mut cache_changed = false;
+
+    // Use the perceptual hashes stored in the index and calculate the missing ones (except for files that are known to be unhashable)
+    let mut hashes: Vec<Option<u64>> = photos
+        .iter()
+        .map(|photo| {
+            let entry = &index.photos[*entry_indices.get(&photo.relative_path)?];
+            u64::from_str_radix(entry.perceptual_hash.as_deref()?, 16).ok()
+        })
+        .collect();
+    let mut photos_to_hash: Vec<Photo> = vec![];
+    for (photo, hash) in photos.iter().zip(hashes.iter()) {
+        if hash.is_some() {
+            continue;
+        }
+
+        let filehash = entry_indices
+            .get(&photo.relative_path)
+            .map(|i| &index.photos[*i].filehash);
+        match filehash.and_then(|h| cache.unhashable.get(h)) {
+            Some(reason) => report.undecodable.push((photo.relative_path.clone(), reason.clone())),
+            None => photos_to_hash.push(photo.clone()),
+        }
+    }
+    let mut calculated_hashes = photos_to_hash
+        .iter()
+        .zip(calc_perceptual_hashes(root_dir, &photos_to_hash))
+        .map(|(photo, res)| (&photo.relative_path, res))
+        .collect::<HashMap<_, _>>();
+
+    for (photo, hash) in photos.iter().zip(hashes.iter_mut()) {
+        match calculated_hashes.remove(&photo.relative_path) {
+            Some(Ok(calculated_hash)) => {
+                *hash = Some(calculated_hash);
+                if let Some(i) = entry_indices.get(&photo.relative_path) {
+                    index.photos[*i].perceptual_hash = Some(format!("{:016x}", calculated_hash));
+                    report.index_changed = true;
+                }
+            }
+            Some(Err(e)) => {
+                let reason = e.root_cause().to_string();
+                if let Some(i) = entry_indices.get(&photo.relative_path) {
+                    cache
+                        .unhashable
+                        .insert(index.photos[*i].filehash.clone(), reason.clone());
+                    cache_changed = true;
+                }
+                report.undecodable.push((photo.relative_path.clone(), reason));
+            }
+            None => {}
+        }
+    }
+    report.undecodable.sort();
+
+    // Only keep the hashes of indexed files to prevent the cache from growing indefinitely
+    let indexed_hashes: HashSet<&String> = index.photos.iter().map(|e| &e.filehash).collect();
+    let cache_size = cache.unhashable.len();
+    cache.unhashable.retain(|h, _| indexed_hashes.contains(h));
+    cache_changed |= cache.unhashable.len() != cache_size;
+    if cache_changed {
+        if dry_run {
+            debug!("Dupes cache not written (running in dry-run mode).");
+        } else if let Err(e) = write_cache_file(root_dir, DUPES_CACHE_FILE_NAME, DUPES_CACHE_VERSION, cache) {
+            warn!("Could not write dupes cache: {:#}", e);
+        }
+    }
+
+    // Find the similar preceding photos of each photo (comparing all pairs, which is cheap compared to decoding the photos)
+    let hashes: Vec<(usize, u64)> = hashes
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, hash)| hash.map(|h| (i, h)))
+        .collect();
+    let distance = |a: usize, b: usize| (hashes[a].1 ^ hashes[b].1).count_ones();
+    let similar_photos: Vec<Vec<usize>> = (0..hashes.len())
+        .into_par_iter()
+        .map(|a| (0..a).filter(|b| distance(a, *b) <= threshold).collect())
+        .collect();
+
+    // Form clusters whose photos are all similar to each other, so that unrelated photos are not chained together by photos that are
+    // similar to both of them: Each photo joins the first cluster of its similar preceding photos whose photos are all similar to it
+    let mut clusters: Vec<Vec<usize>> = vec![];
+    let mut photo_clusters: Vec<usize> = Vec::with_capacity(hashes.len());
+    for (a, similar) in similar_photos.iter().enumerate() {
+        let cluster = similar
+            .iter()
+            .map(|b| photo_clusters[*b])
+            .find(|c| clusters[*c].iter().all(|b| distance(a, *b) <= threshold));
+        match cluster {
+            Some(c) => {
+                clusters[c].push(a);
+                photo_clusters.push(c);
+            }
+            None => {
+                photo_clusters.push(clusters.len());
+                clusters.push(vec![a]);
+            }
+        }
+    }
+
+    // Note: The photos are already sorted by path, so that the clusters and the photos within them are as well
+    report.clusters = clusters
+        .into_iter()
+        .filter(|cluster| cluster.len() > 1)
+        .map(|cluster| {
+            cluster
+                .iter()
+                .map(|a| SimilarPhoto {
+                    path: photos[hashes[*a].0].relative_path.clone(),
+                    distance: distance(cluster[0], *a),
+                })
+                .collect()
+        })
+        .collect();
+
+    if !report.clusters.is_empty() {
+        let html_path = root_dir.join(subdir).join(output_filename);
+        write_dupes_report(root_dir, &html_path, &photos, &report.clusters, resize_width)?;
+        info!("File {} generated successfully.", html_path.display());
+    }
+
+    Ok(report)
+}
+
+/// Writes the given clusters of similar photos to a HTML file, showing the thumbnails of the photos of each cluster side by side.
+fn write_dupes_report(
+    root_dir: &Path,
+    html_path: &Path,
+    photos: &[Photo],
+    clusters: &[Vec<SimilarPhoto>],
+    resize_width: u32,
+) -> Result<()> {
+    let photos: HashMap<&Path, &Photo> = photos.iter().map(|p| (p.relative_path.as_path(), p)).collect();
+
+    let mut f = File::create(html_path).with_context(|| format!("Could not write to {}!", html_path.display()))?;
+    writeln!(&mut f, "<!DOCTYPE html>")?;
+    writeln!(&mut f, "<html lang=\"en\">")?;
+    writeln!(&mut f, "<head>")?;
+    writeln!(&mut f, "<meta charset=\"utf-8\">")?;
+    writeln!(&mut f, "<title>Similar Photos</title>")?;
+    writeln!(
+        &mut f,
+        "<style>h1 {{ font-size: large }} .cluster {{ display: flex; flex-wrap: wrap; gap: 1em }} figure {{ margin: 0; width: {}px }} \
+         figcaption {{ font-size: small; overflow-wrap: anywhere }}</style>",
+        resize_width
+    )?;
+    writeln!(&mut f, "</head>")?;
+    writeln!(&mut f, "<body>")?;
+
+    for (i, cluster) in clusters.iter().enumerate() {
+        let thumbnails: Vec<_> = cluster
+            .par_iter()
+            .map(|p| photos[p.path.as_path()].get_thumbnail(root_dir, resize_width))
+            .collect();
+
+        writeln!(&mut f, "<h1>Cluster {} ({} photos)</h1>", i + 1, cluster.len())?;
+        writeln!(&mut f, "<div class=\"cluster\">")?;
+        for (photo, data) in cluster.iter().zip(thumbnails) {
+            writeln!(&mut f, "<figure>")?;
+            match data {
+                Ok(bytes) => writeln!(&mut f, "{}", thumbnail_img_element(&bytes))?,
+                Err(e) => writeln!(&mut f, "<p>{}</p>", encode_safe(&e.to_string()))?,
+            }
+            let filesize = fs::metadata(root_dir.join(&photo.path)).map(|md| format_bytes(md.len()));
+            writeln!(
+                &mut f,
+                "<figcaption>{}<br />{}, distance {}</figcaption>",
+                encode_safe(&photo.path.display().to_string()),
+                filesize.as_deref().unwrap_or("unknown size"),
+                photo.distance
+            )?;
+            writeln!(&mut f, "</figure>")?;
+        }
+        writeln!(&mut f, "</div>")?;
+    }
+
+    writeln!(&mut f, "</body>")?;
+    writeln!(&mut f, "</html>")?;
+
+    Ok(())
 }
 
 /// Reads a thumbnail catalogue (HTML file) and extracts the filenames of all contained photos. This function is used to avoid
@@ -43,40 +346,656 @@ fn extract_entries_from_thumbcat(html_path: &Path) -> Result<Vec<String>> {
     Ok(entries)
 }
 
+/// Position recorded in a GPS track log.
+struct TrackPoint {
+    time: DateTime<Utc>,
+    location: (f64, f64),
+    elevation: Option<f64>,
+}
+
+/// Reads all track points that have a timestamp from the given GPX files and returns them sorted by time.
+fn read_track_points(gpx_files: &[PathBuf]) -> Result<Vec<TrackPoint>> {
+    let mut points = vec![];
+
+    for gpx_file in gpx_files {
+        let file =
+            File::open(gpx_file).with_context(|| format!("Could not open {} for reading!", gpx_file.display()))?;
+        let gpx_data =
+            read(BufReader::new(file)).with_context(|| format!("Could not parse GPX file {}!", gpx_file.display()))?;
+
+        let waypoints = gpx_data
+            .tracks
+            .iter()
+            .flat_map(|t| t.segments.iter())
+            .flat_map(|s| s.points.iter());
+        for wp in waypoints {
+            let Some(time) = wp.time.and_then(|t| t.format().ok()) else {
+                continue;
+            };
+            let time = DateTime::parse_from_rfc3339(&time)
+                .with_context(|| format!("Invalid timestamp in GPX file {}: {}", gpx_file.display(), time))?;
+            let point = wp.point();
+
+            points.push(TrackPoint {
+                time: time.with_timezone(&Utc),
+                location: (point.y(), point.x()),
+                elevation: wp.elevation,
+            });
+        }
+    }
+
+    points.sort_by_key(|p| p.time);
+    Ok(points)
+}
+
+/// Determines the position at the given time by linearly interpolating between the surrounding track points. Returns None if the time is
+/// not covered by the track points or the surrounding track points are more than the given maximum gap (in seconds) apart.
+fn interpolate_position(points: &[TrackPoint], time: DateTime<Utc>, max_gap: i64) -> Option<((f64, f64), Option<f64>)> {
+    let i = points.partition_point(|p| p.time < time);
+    let after = points.get(i)?;
+    if after.time == time {
+        return Some((after.location, after.elevation));
+    }
+
+    let before = points.get(i.checked_sub(1)?)?;
+    let gap = (after.time - before.time).num_milliseconds();
+    if gap > max_gap * 1000 {
+        return None;
+    }
+
+    let f = (time - before.time).num_milliseconds() as f64 / gap as f64;
+    let lerp = |a: f64, b: f64| a + (b - a) * f;
+    let location = (
+        lerp(before.location.0, after.location.0),
+        lerp(before.location.1, after.location.1),
+    );
+    let elevation = match (before.elevation, after.elevation) {
+        (Some(a), Some(b)) => Some(lerp(a, b)),
+        _ => None,
+    };
+
+    Some((location, elevation))
+}
+
+/// Writes the given GPS position into the EXIF data of the given JPEG file (path relative to the root directory), recording the change in
+/// the journal and updating the corresponding index entry.
+fn write_gps_position(
+    root_dir: &Path,
+    filepath: &Path,
+    location: (f64, f64),
+    elevation: Option<f64>,
+    index: &mut Index,
+    journal: &mut Journal,
+) -> Result<()> {
+    let full_path = root_dir.join(filepath);
+    let data = fs::read(&full_path).with_context(|| format!("Could not read {}!", full_path.display()))?;
+
+    // Replace existing EXIF segment or insert new one
+    let segment = find_exif_segment(&data)?;
+    let new_tiff_data = add_gps_location(
+        segment.as_ref().map(|s| exif_segment_tiff_data(&data, s)),
+        location,
+        elevation,
+    )?;
+    let new_segment = create_exif_segment(&new_tiff_data)?;
+    let (offset, replaced_length) = match segment {
+        Some(s) => (s.offset, s.length),
+        None => (exif_segment_insertion_offset(&data)?, 0),
+    };
+
+    let new_length = new_segment.len();
+    let mut new_data = data.clone();
+    new_data.splice(offset..offset + replaced_length, new_segment);
+
+    // Verify that the EXIF data can be read back before writing the file
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(&new_data))
+        .context("Could not read back written EXIF data!")?;
+    if exif.get_field(exif::Tag::GPSLatitude, exif::In::PRIMARY).is_none() {
+        bail!("Written EXIF data does not contain the GPS position!");
+    }
+
+    let new_hash = calc_data_hash(&new_data);
+    journal.record(JournalChange::ReplaceBytes {
+        path: filepath.to_owned(),
+        offset: offset as u64,
+        new_length: new_length as u64,
+        original_bytes: STANDARD.encode(&data[offset..offset + replaced_length]),
+        original_hash: calc_data_hash(&data),
+        new_hash: new_hash.clone(),
+    })?;
+
+    replace_file_contents(&full_path, &new_data)?;
+    update_index_entry_hash(root_dir, index, filepath, new_hash)
+}
+
+/// Geotags the photos in the given directory (and potentially subdirectories) that do not have a location yet, using the positions from
+/// the given GPX track logs (see description of geotag CLI command). Returns how many photos have been geotagged by the function.
+#[allow(clippy::too_many_arguments)]
+pub fn geotag(
+    root_dir: &Path,
+    subdir: &Path,
+    index: &mut Index,
+    photos: &[Photo],
+    gpx_files: &[PathBuf],
+    max_gap: &str,
+    time_offset: &str,
+    time_zone: Option<&str>,
+    recursive: bool,
+    dry_run: bool,
+) -> Result<usize> {
+    let max_gap = parse_time_offset(max_gap)?;
+    let time_offset = chrono::Duration::seconds(parse_time_offset(time_offset)?);
+    let time_zone = time_zone
+        .map(|tz| parse_utc_offset(tz).with_context(|| format!("Invalid UTC offset: {}", tz)))
+        .transpose()?;
+
+    let points = read_track_points(gpx_files)?;
+    match (points.first(), points.last()) {
+        (Some(first), Some(last)) => info!(
+            "Read {} track points from {} to {}.",
+            points.len(),
+            first.time.with_timezone(&Local).format("%d.%m.%Y %H:%M:%S"),
+            last.time.with_timezone(&Local).format("%d.%m.%Y %H:%M:%S")
+        ),
+        _ => bail!("No track points with timestamps found in the given GPX files!"),
+    }
+
+    let cur_photos = get_photos_in_subdir(photos, subdir, recursive);
+    let index_map: HashMap<&Path, &IndexEntry> = index.photos.iter().map(|p| (p.filepath.as_path(), p)).collect();
+    let metadata: Vec<_> = cur_photos
+        .par_iter()
+        .map(|p| {
+            let filehash = index_map.get(p.relative_path.as_path()).map(|e| e.filehash.as_str());
+            read_photo_metadata(root_dir, &p.relative_path, filehash, &index.user_config)
+        })
+        .collect();
+
+    let mut journal = Journal::begin(root_dir, "geotag");
+    let mut geotagged_photo_count = 0;
+
+    for (photo, pmd) in cur_photos.iter().zip(metadata) {
+        let path = &photo.relative_path;
+
+        let pmd = match pmd {
+            Ok(pmd) => pmd,
+            Err(e) => {
+                warn!("{}: Could not read EXIF data - {}", path.display(), e);
+                continue;
+            }
+        };
+
+        if pmd.location.is_some() {
+            debug!("{}: Photo already has a location", path.display());
+            continue;
+        }
+
+        let Some(timestamp) = pmd.timestamp else {
+            warn!("{}: EXIF timestamp not set", path.display());
+            continue;
+        };
+
+        let utc = match (timestamp.to_utc(), time_zone) {
+            (Some(utc), _) => utc,
+            (None, Some(tz)) => PhotoTimestamp {
+                offset: Some(tz),
+                ..timestamp
+            }
+            .to_utc()
+            .context("Invalid local timestamp!")?,
+            (None, None) => {
+                warn!(
+                    "{}: UTC offset of timestamp unknown (set --time-zone to assume one)",
+                    path.display()
+                );
+                continue;
+            }
+        };
+
+        let Some((location, elevation)) = interpolate_position(&points, utc + time_offset, max_gap) else {
+            debug!("{}: No position found in track logs", path.display());
+            continue;
+        };
+
+        let extension = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+        if extension != "jpg" && extension != "jpeg" {
+            warn!(
+                "{}: Writing GPS tags is only supported for JPEG files, skipping file",
+                path.display()
+            );
+            continue;
+        }
+
+        if dry_run {
+            info!(
+                "{}: Would set location to {:.5},{:.5} (running in dry-run mode)",
+                path.display(),
+                location.0,
+                location.1
+            );
+        } else {
+            info!(
+                "{}: Setting location to {:.5},{:.5}",
+                path.display(),
+                location.0,
+                location.1
+            );
+
+            if let Err(e) = write_gps_position(root_dir, path, location, elevation, index, &mut journal) {
+                error!("{}: Could not write GPS tags - {}", path.display(), e);
+                continue;
+            }
+        }
+
+        geotagged_photo_count += 1;
+    }
+
+    Ok(geotagged_photo_count)
+}
+
+/// Number of imported photos after which the index file is written during an import, so that the index entries of the photos imported so
+/// far are not lost if the import is interrupted.
+const IMPORT_INDEX_WRITE_INTERVAL: usize = 100;
+
+/// Determines the path (relative to the root directory) to which a photo is imported: The directory is derived from the photo timestamp
+/// using the given directory scheme (falling back to the modification time of the file if the photo has no timestamp) and the filename is
+/// the canonical filename (falling back to the original filename if it cannot be determined).
+fn get_import_path(
+    source_path: &Path,
+    filehash: &str,
+    pmd: Result<PhotoMetaData>,
+    directory_scheme: &str,
+    naming_scheme: &NamingScheme,
+    user_config: &UserConfig,
+) -> Result<PathBuf> {
+    let orig_filename = source_path.file_name().context("Invalid filename!")?;
+
+    let (timestamp, filename) = match pmd {
+        Ok(pmd) if pmd.timestamp.is_some() => {
+            // Note: The sequence number within the target directory is determined by the rename done after the import, so a provisional
+            //       one is used here
+            let filename = get_canonical_photo_filename(
+                source_path,
+                &pmd,
+                naming_scheme,
+                user_config,
+                &orig_filename.to_string_lossy(),
+                Some(filehash),
+                Some(1),
+            )?;
+            (pmd.timestamp.unwrap(), PathBuf::from(filename))
+        }
+        res => {
+            if let Err(e) = res {
+                debug!("{}: Could not read EXIF data - {}", source_path.display(), e);
+            }
+            warn!(
+                "{}: EXIF timestamp not set, keeping original filename and using modification time for the directory",
+                source_path.display()
+            );
+
+            let (_, modification_time) = get_file_metadata(source_path)?;
+            let modification_time = modification_time.with_timezone(&Local);
+            let timestamp = PhotoTimestamp {
+                local: modification_time.naive_local(),
+                offset: Some(*modification_time.offset()),
+            };
+            (timestamp, PathBuf::from(orig_filename))
+        }
+    };
+
+    Ok(format_photo_directory(&timestamp, directory_scheme, user_config)?.join(filename))
+}
+
+/// Finds a free path (relative to the root directory) for a file that is moved or copied into the collection, starting with the given path
+/// and inserting the collision suffix if the path is already taken by another file or by a file moved or copied before in the same run. If
+/// the hash of the file is given, a path where a file with the same hash already exists (e.g., from an interrupted import) is returned as
+/// well. Returns the path and whether a file with the same hash already exists there.
+fn find_target_path(
+    root_dir: &Path,
+    path: &Path,
+    filehash: Option<&str>,
+    collision_suffix: &str,
+    taken_paths: &HashSet<PathBuf>,
+) -> Result<(PathBuf, bool)> {
+    let filename = path.file_name().unwrap_or_default().to_string_lossy();
+
+    for counter in 0.. {
+        let candidate = if counter == 0 {
+            path.to_owned()
+        } else if collision_suffix.contains("%{counter}") {
+            path.with_file_name(add_collision_suffix(&filename, collision_suffix, counter))
+        } else {
+            bail!(
+                "Cannot use {}: Target already exists and the collision suffix does not contain %{{counter}}.",
+                path.display()
+            );
+        };
+
+        if taken_paths.contains(&candidate) {
+            continue;
+        }
+
+        let full_path = root_dir.join(&candidate);
+        if !full_path.exists() {
+            return Ok((candidate, false));
+        }
+
+        if let Some(filehash) = filehash {
+            if calc_photo_hash(&full_path)? == filehash {
+                return Ok((candidate, true));
+            }
+        }
+    }
+
+    unreachable!()
+}
+
+/// Copies a file into the collection (target path relative to the root directory), preserving its modification time. The file is copied
+/// to a temporary file first, which is verified against the given hash and only then renamed to the target path (recording the creation
+/// of the file in the journal before), so an interrupted copy never leaves a partial file at the target path.
+fn copy_file_verified(
+    source_path: &Path,
+    root_dir: &Path,
+    target: &Path,
+    filehash: &str,
+    journal: &mut Journal,
+) -> Result<()> {
+    let full_target = root_dir.join(target);
+    if let Some(parent) = full_target.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Could not create directory {}!", parent.display()))?;
+    }
+
+    // Note: The temporary file has an extension that is not considered as photo, so it is ignored when scanning the collection
+    let temp_path = full_target.with_file_name(format!(
+        "{}.po_import",
+        full_target.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    fs::copy(source_path, &temp_path)
+        .with_context(|| format!("Could not copy {} to {}!", source_path.display(), temp_path.display()))?;
+
+    let (_, modification_time) = get_file_metadata(source_path)?;
+    let file = File::options().write(true).open(&temp_path)?;
+    file.set_modified(modification_time.into())?;
+    file.sync_all()?;
+    drop(file);
+
+    let copied_hash = calc_photo_hash(&temp_path)?;
+    if copied_hash != filehash {
+        fs::remove_file(&temp_path)?;
+        bail!(
+            "Copy of {} is corrupted (hash {} but expected {})!",
+            source_path.display(),
+            copied_hash,
+            filehash
+        );
+    }
+
+    journal.record(JournalChange::Create {
+        path: target.to_owned(),
+        filehash: filehash.to_string(),
+    })?;
+    fs::rename(&temp_path, &full_target).with_context(|| format!("Could not move copy to {}!", target.display()))
+}
+
+/// Imports the photos (together with their companions) from the given source directory into the collection (see description of import CLI
+/// command). Returns how many files have been imported by the function.
+pub fn import(
+    root_dir: &Path,
+    index: &mut Index,
+    photos: &[Photo],
+    source_dir: &Path,
+    directory_scheme: Option<&str>,
+    dry_run: bool,
+) -> Result<usize> {
+    let source_dir = source_dir
+        .canonicalize()
+        .with_context(|| format!("Could not open source directory {}!", source_dir.display()))?;
+    if source_dir.starts_with(root_dir.canonicalize()?) {
+        bail!(
+            "Source directory {} is part of the photo collection!",
+            source_dir.display()
+        );
+    }
+
+    let directory_scheme = directory_scheme
+        .unwrap_or(&index.user_config.import_directory_scheme)
+        .to_string();
+    validate_directory_scheme(&directory_scheme)?;
+    let naming_scheme = NamingScheme::parse(&index.user_config.file_naming_scheme)?;
+
+    // Hash and read meta data of all files in the source directory
+    let source_photos = scan_photo_collection(&index.user_config, &source_dir)?;
+    let filepaths: Vec<PathBuf> = source_photos.iter().flat_map(|p| p.files().cloned()).collect();
+    info!("Found {} photos in {}.", source_photos.len(), source_dir.display());
+
+    let hashes = calc_photo_hashes(&source_dir, &filepaths);
+    let metadata: Vec<_> = filepaths
+        .par_iter()
+        .map(|p| read_external_photo_metadata(&source_dir.join(p), &index.user_config))
+        .collect();
+
+    let mut known_hashes: HashMap<String, PathBuf> = index
+        .photos
+        .iter()
+        .map(|p| (p.filehash.clone(), p.filepath.clone()))
+        .collect();
+    let mut taken_paths: HashSet<PathBuf> = HashSet::new();
+    let mut imported_paths: Vec<PathBuf> = vec![];
+    let mut journal = Journal::begin(root_dir, "import");
+    let mut files = filepaths.iter().zip(hashes).zip(metadata);
+
+    for photo in source_photos.iter() {
+        // Path of the photo within the collection, next to which its companions are imported
+        let mut photo_target: Option<PathBuf> = None;
+
+        for ((filepath, hash), pmd) in files.by_ref().take(photo.companions.len() + 1) {
+            let source_path = source_dir.join(filepath);
+            let is_companion = *filepath != photo.relative_path;
+
+            let hash = match hash {
+                Ok(hash) => hash,
+                Err(e) => {
+                    error!("{}: Could not hash file - {}", filepath.display(), e);
+                    continue;
+                }
+            };
+
+            // Skip files that are already part of the collection (or have been imported before in this run from another file)
+            if let Some(existing_path) = known_hashes.get(&hash) {
+                info!("{}: Already in collection, skipping file", filepath.display());
+                if !is_companion {
+                    photo_target = Some(existing_path.clone());
+                }
+                continue;
+            }
+
+            let target = match (&photo_target, is_companion) {
+                (Some(photo_target), true) => {
+                    let photo_target_name = photo_target.file_name().unwrap_or_default().to_string_lossy();
+                    Ok(photo_target.with_file_name(get_companion_filename(
+                        &photo.relative_path,
+                        filepath,
+                        &photo_target_name,
+                    )))
+                }
+                _ => get_import_path(
+                    &source_path,
+                    &hash,
+                    pmd,
+                    &directory_scheme,
+                    &naming_scheme,
+                    &index.user_config,
+                ),
+            };
+            let target = target.and_then(|p| {
+                find_target_path(
+                    root_dir,
+                    &p,
+                    Some(&hash),
+                    &index.user_config.file_naming_collision_suffix,
+                    &taken_paths,
+                )
+            });
+            let (target, already_copied) = match target {
+                Ok(target) => target,
+                Err(e) => {
+                    error!("{}: Could not determine target path - {}", filepath.display(), e);
+                    continue;
+                }
+            };
+
+            taken_paths.insert(target.clone());
+            known_hashes.insert(hash.clone(), target.clone());
+            if !is_companion {
+                photo_target = Some(target.clone());
+            }
+
+            if dry_run {
+                info!(
+                    "{}: Would import file to {} (running in dry-run mode)",
+                    filepath.display(),
+                    target.display()
+                );
+                imported_paths.push(target);
+                continue;
+            }
+
+            if already_copied {
+                info!(
+                    "{}: Already copied to {} by a previous import, adding it to the index",
+                    filepath.display(),
+                    target.display()
+                );
+            } else {
+                info!("{}: Importing file to {}", filepath.display(), target.display());
+
+                if let Err(e) = copy_file_verified(&source_path, root_dir, &target, &hash, &mut journal) {
+                    // Keep the photos imported so far, so that the import can be resumed
+                    write_index_file(root_dir, index)?;
+                    return Err(e.context("Import aborted! Run import again to resume it."));
+                }
+            }
+
+            let (filesize, modification_time) = get_file_metadata(&root_dir.join(&target))?;
+            index.photos.push(IndexEntry {
+                filepath: target.clone(),
+                orig_filename: filepath.file_name().unwrap_or_default().to_string_lossy().into(),
+                filehash: hash,
+                filesize: Some(filesize),
+                modification_time: Some(modification_time),
+                last_verified: Some(Utc::now()),
+                perceptual_hash: None,
+            });
+            imported_paths.push(target);
+
+            if imported_paths.len().is_multiple_of(IMPORT_INDEX_WRITE_INTERVAL) {
+                write_index_file(root_dir, index)?;
+            }
+        }
+    }
+
+    // Rename the photos in the target directories to resolve collisions of the imported photos (and photos already stored there) in the
+    // same way as the rename command would
+    if !dry_run && !imported_paths.is_empty() {
+        let filepaths = photos.iter().flat_map(|p| p.files().cloned());
+        rename_in_directories_of(root_dir, index, filepaths, &imported_paths, &mut journal)?;
+    }
+
+    Ok(imported_paths.len())
+}
+
+/// Entry written by the list command in JSON output format.
+#[derive(Serialize)]
+struct ListEntry<'a> {
+    /// Path of the photo (relative to the root directory)
+    path: PathBuf,
+
+    companions: Vec<PathBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<PhotoMetaData>,
+
+    /// Error message if the meta data could not be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata_error: Option<String>,
+
+    /// Index entry of the photo (missing if the photo is not indexed)
+    index_entry: Option<&'a IndexEntry>,
+}
+
 /// Show meta data from EXIF tags and the index file for image files within the current directory.
-pub fn list(root_dir: &Path, subdir: &Path, index: &Index, photos: &[Photo], recursive: bool) -> Result<()> {
+pub fn list(
+    root_dir: &Path,
+    subdir: &Path,
+    index: &Index,
+    photos: &[Photo],
+    recursive: bool,
+    format: OutputFormat,
+) -> Result<()> {
     let cur_photos = get_photos_in_subdir(photos, subdir, recursive);
 
     // Create HashMap from index for efficient lookup
     let index_map: HashMap<PathBuf, &IndexEntry> = index.photos.iter().map(|p| (p.filepath.clone(), p)).collect();
+    let mut entries = vec![];
 
     for photo in cur_photos {
         let path = photo.relative_path;
+        let index_entry = index_map.get(&path).copied();
+        let metadata = read_photo_metadata(
+            root_dir,
+            &path,
+            index_entry.map(|e| e.filehash.as_str()),
+            &index.user_config,
+        );
+
+        if format != OutputFormat::Text {
+            let (metadata, metadata_error) = match metadata {
+                Ok(pmd) => (Some(pmd), None),
+                Err(e) => (None, Some(format!("{:#}", e))),
+            };
+            entries.push(ListEntry {
+                path,
+                companions: photo.companions,
+                metadata,
+                metadata_error,
+                index_entry,
+            });
+            continue;
+        }
+
         let rel_path = path
             .strip_prefix(subdir)
             .expect("Path not in subdir! (should never happen)");
 
         // Read EXIF data of photo
-        let exif_str = match read_exif_data(&root_dir.join(&path)) {
+        let exif_str = match metadata {
             Ok(pmd) => {
-                format!(
+                let mut s = format!(
                     "{} / {} / {} / loc: {},{},{}",
                     pmd.make.as_deref().unwrap_or("<unknown make>"),
                     pmd.model.as_deref().unwrap_or("<unknown model>"),
-                    pmd.timestamp_local
-                        .map(|ts| ts.format("%d.%m.%Y %H:%M").to_string())
+                    pmd.timestamp
+                        .map(|ts| ts.to_string())
                         .as_deref()
                         .unwrap_or("unknown time"),
                     pmd.location.map(|l| format!("{:.4}", l.0)).as_deref().unwrap_or("?"),
                     pmd.location.map(|l| format!("{:.4}", l.1)).as_deref().unwrap_or("?"),
                     pmd.altitude.map(|a| a.to_string() + "m").as_deref().unwrap_or("?")
-                )
+                );
+                if let Some((width, height)) = pmd.dimensions {
+                    s += &format!(" / {}x{}", width, height);
+                }
+                if let Some(duration) = pmd.duration {
+                    s += &format!(" / {:.1}s", duration);
+                }
+                s
             }
-            Err(_) => "Could not read EXIF data".into(),
+            Err(_) => "Could not read meta data".into(),
         };
 
         // Check original filename from index ()
-        let index_str = match index_map.get(&path) {
+        let index_str = match index_entry {
             Some(ie) => format!("orig name: {}", ie.orig_filename),
             None => "photo not indexed!".into(),
         };
@@ -84,12 +1003,20 @@ pub fn list(root_dir: &Path, subdir: &Path, index: &Index, photos: &[Photo], rec
         info!("{}: {} / {}", rel_path.display(), exif_str, index_str);
     }
 
-    Ok(())
+    print_records(format, &entries)
 }
 
 /// Exports the GPS locations of the image files within the current directory in the GPX format and shows them on a map
-pub fn map(root_dir: &Path, subdir: &Path, photos: &[Photo], recursive: bool, command: Option<&str>) -> Result<()> {
+pub fn map(
+    root_dir: &Path,
+    subdir: &Path,
+    index: &Index,
+    photos: &[Photo],
+    recursive: bool,
+    command: Option<&str>,
+) -> Result<()> {
     let cur_photos = get_photos_in_subdir(photos, subdir, recursive);
+    let index_map: HashMap<&Path, &IndexEntry> = index.photos.iter().map(|p| (p.filepath.as_path(), p)).collect();
 
     // Create GPX data structure for writing
     let mut gpx_data = Gpx {
@@ -99,10 +1026,10 @@ pub fn map(root_dir: &Path, subdir: &Path, photos: &[Photo], recursive: bool, co
 
     for photo in cur_photos {
         let path = photo.relative_path;
-        let full_path = &root_dir.join(&path);
 
         // Read EXIF data of photo
-        match read_exif_data(full_path) {
+        let filehash = index_map.get(path.as_path()).map(|e| e.filehash.as_str());
+        match read_photo_metadata(root_dir, &path, filehash, &index.user_config) {
             Ok(pmd) => {
                 if let Some(location) = pmd.location {
                     let mut wp = Waypoint::new(Point::new(location.1, location.0));
@@ -112,98 +1039,634 @@ pub fn map(root_dir: &Path, subdir: &Path, photos: &[Photo], recursive: bool, co
 
                     gpx_data.waypoints.push(wp);
                 } else {
-                    warn!("No location found in EXIF data from {}!", path.display());
+                    warn!("No location found in meta data of {}!", path.display());
+                }
+            }
+            Err(e) => {
+                warn!("Could not read meta data from {}: {}", path.display(), e);
+            }
+        };
+    }
+
+    // Write GPX data to file
+    // TODO: Use proper temporary file instead of hardcoded one
+    let file = File::create("/tmp/photo_locations.gpx").context("Could not open GPX file for writing!")?;
+    write(&gpx_data, &file)?;
+    file.sync_all()?;
+    drop(file);
+
+    // Invoke external tool to visualize the GPX data using a map
+    if let Some(command) = command {
+        info!("Invoking external command {}...", command);
+        Command::new(command).args(["/tmp/photo_locations.gpx"]).spawn()?;
+    }
+
+    Ok(())
+}
+
+/// Moves the photos (together with their companions) in the given directory (and potentially subdirectories) into the directories given by
+/// the directory naming scheme configured in the index, updating the paths of the corresponding index entries accordingly (also in dry-run
+/// mode, like rename). Afterwards, the photos in the target directories are renamed to resolve name collisions. Returns how many photos
+/// have been moved by the function.
+pub fn organize(
+    root_dir: &Path,
+    subdir: &Path,
+    index: &mut Index,
+    photos: &[Photo],
+    recursive: bool,
+    dry_run: bool,
+) -> Result<usize> {
+    let Some(directory_scheme) = index.user_config.directory_naming_scheme.clone() else {
+        bail!("No directory naming scheme configured in the index file!");
+    };
+
+    let cur_photos = get_photos_in_subdir(photos, subdir, recursive);
+    let filepaths: Vec<PathBuf> = cur_photos.iter().map(|p| p.relative_path.clone()).collect();
+    let canonical_dirs = get_canonical_photo_directories(root_dir, &filepaths, index, &directory_scheme);
+
+    // Check for each photo whether it should be moved
+    let mut moves = vec![];
+    let mut moved_photo_count = 0;
+    let mut taken_paths = HashSet::new();
+    for (photo, canonical_dir) in cur_photos.iter().zip(canonical_dirs) {
+        let filepath = &photo.relative_path;
+        let targets = canonical_dir.and_then(|dir| {
+            if filepath.parent() == Some(dir.as_path()) {
+                return Ok(None);
+            }
+
+            let target = dir.join(filepath.file_name().unwrap_or_default());
+            let (target, _) = find_target_path(
+                root_dir,
+                &target,
+                None,
+                &index.user_config.file_naming_collision_suffix,
+                &taken_paths,
+            )?;
+
+            // Companions keep their names unless the photo has to be renamed to resolve a collision in the target directory
+            let mut targets = vec![(filepath.clone(), target.clone())];
+            for companion in photo.companions.iter() {
+                let companion_target = if target.file_name() == filepath.file_name() {
+                    dir.join(companion.file_name().unwrap_or_default())
+                } else {
+                    let target_name = target.file_name().unwrap_or_default().to_string_lossy();
+                    dir.join(get_companion_filename(filepath, companion, &target_name))
+                };
+
+                if taken_paths.contains(&companion_target) || root_dir.join(&companion_target).exists() {
+                    bail!(
+                        "Cannot move companion file {} to {}: Target already exists.",
+                        companion.display(),
+                        companion_target.display()
+                    );
+                }
+                targets.push((companion.clone(), companion_target));
+            }
+
+            Ok(Some(targets))
+        });
+
+        match targets {
+            Ok(Some(targets)) => {
+                taken_paths.extend(targets.iter().map(|(_, target)| target.clone()));
+                moves.extend(targets);
+                moved_photo_count += 1;
+            }
+            Ok(None) => {
+                debug!("{}: Move not necessary", filepath.display());
+            }
+            Err(e) => {
+                warn!("{}: Could not process file - {}", filepath.display(), e);
+            }
+        }
+    }
+
+    for (old, new) in moves.iter() {
+        if dry_run {
+            info!(
+                "{}: Would move file to {} (running in dry-run mode)",
+                old.display(),
+                new.display()
+            );
+        } else {
+            info!("{}: Moving file to {}", old.display(), new.display());
+        }
+    }
+
+    let mut journal = Journal::begin(root_dir, "organize");
+
+    if !dry_run && !moves.is_empty() {
+        let hashes = get_file_hashes(root_dir, index, moves.iter().map(|(old, _)| old))?;
+
+        for (old, new) in moves.iter() {
+            if let Some(parent) = root_dir.join(new).parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Could not create directory {}!", parent.display()))?;
+            }
+            move_file(root_dir, old, new, &hashes[old], &mut journal)?;
+        }
+
+        remove_empty_directories(root_dir, moves.iter().filter_map(|(old, _)| old.parent()));
+    }
+
+    update_index_paths(index, &moves);
+
+    // Rename the photos in the target directories to resolve collisions of the moved photos (and photos already stored there)
+    if !dry_run && !moves.is_empty() {
+        let move_map: HashMap<&Path, &Path> = moves.iter().map(|(old, new)| (old.as_path(), new.as_path())).collect();
+        let filepaths = photos.iter().flat_map(|p| p.files()).map(|filepath| {
+            move_map
+                .get(filepath.as_path())
+                .map(|new| new.to_path_buf())
+                .unwrap_or_else(|| filepath.clone())
+        });
+        let moved_paths: Vec<PathBuf> = moves.iter().map(|(_, new)| new.clone()).collect();
+        rename_in_directories_of(root_dir, index, filepaths, &moved_paths, &mut journal)?;
+    }
+
+    Ok(moved_photo_count)
+}
+
+/// Removes the given directories (relative to the root directory) and their parent directories if they are empty (e.g., after all photos
+/// have been moved out of them).
+fn remove_empty_directories<'a>(root_dir: &Path, dirs: impl Iterator<Item = &'a Path>) {
+    let dirs: BTreeSet<&Path> = dirs.collect();
+
+    // Note: Iterating in reverse order handles subdirectories before their parent directories
+    for dir in dirs.into_iter().rev() {
+        for dir in dir.ancestors().filter(|d| !d.as_os_str().is_empty()) {
+            if fs::remove_dir(root_dir.join(dir)).is_err() {
+                break;
+            }
+            debug!("Removed empty directory {}", dir.display());
+        }
+    }
+}
+
+/// Renames the photos containing the given changed files (of all files of the collection given by their paths relative to the root
+/// directory, which are grouped into photos again) to their canonical names, together with the photos in the same directories whose names
+/// collide with them. This is used after photos have been added to directories to resolve collisions between their names and the names of
+/// the photos that were already stored there, without renaming unrelated photos of these directories. Returns how many photos have been
+/// renamed.
+fn rename_in_directories_of(
+    root_dir: &Path,
+    index: &mut Index,
+    filepaths: impl Iterator<Item = PathBuf>,
+    changed_paths: &[PathBuf],
+    journal: &mut Journal,
+) -> Result<usize> {
+    let dirs: HashSet<&Path> = changed_paths.iter().filter_map(|p| p.parent()).collect();
+    let filepaths: BTreeSet<PathBuf> = filepaths
+        .chain(changed_paths.iter().cloned())
+        .filter(|p| p.parent().map(|d| dirs.contains(d)).unwrap_or(false))
+        .collect();
+
+    let (photos, _) = group_photo_files(filepaths.into_iter().collect(), &index.user_config);
+    let changed_paths: HashSet<&PathBuf> = changed_paths.iter().collect();
+    let changed_photos: Vec<bool> = photos
+        .iter()
+        .map(|p| p.files().any(|f| changed_paths.contains(f)))
+        .collect();
+    rename_to_canonical_names(root_dir, index, photos, Some(&changed_photos), false, journal)
+}
+
+/// Drops all of the given groups of renames (one group per photo, containing the renames of the photo and its companions with paths
+/// relative to the root directory) of which any target is already taken by a file that is not renamed itself, so that photos and their
+/// companions are always renamed together. Targets taken by files that are renamed as well are fine since perform_renames() orders the
+/// renames accordingly.
+fn filter_blocked_renames(root_dir: &Path, mut renames: Vec<Vec<(PathBuf, PathBuf)>>) -> Vec<Vec<(PathBuf, PathBuf)>> {
+    // Repeat until stable since dropping a rename may block further renames
+    loop {
+        let sources: HashSet<PathBuf> = renames.iter().flatten().map(|(old, _)| old.clone()).collect();
+        let count_before = renames.len();
+
+        renames.retain(|group| {
+            // Note: Since we just check before rename here, this is not free of race conditions (good enough for now though)
+            // See: https://internals.rust-lang.org/t/rename-file-without-overriding-existing-target/17637
+            match group
+                .iter()
+                .find(|(_, new)| root_dir.join(new).exists() && !sources.contains(new))
+            {
+                Some((old, new)) => {
+                    error!(
+                        "{}: Cannot rename to {}: Target already exists.",
+                        old.display(),
+                        new.display()
+                    );
+                    false
                 }
+                None => true,
+            }
+        });
+
+        if renames.len() == count_before {
+            return renames;
+        }
+    }
+}
+
+/// Performs the given renames (paths relative to the root directory), ordering them such that a file can be renamed to a name that is
+/// currently taken by another file being renamed (e.g., when two photos swap their names). Every move is recorded in the given journal
+/// together with the hash of the moved file (taken from the given map) before it is done.
+fn perform_renames(
+    root_dir: &Path,
+    renames: &[(PathBuf, PathBuf)],
+    hashes: &HashMap<PathBuf, String>,
+    journal: &mut Journal,
+) -> Result<()> {
+    let mut pending: Vec<(PathBuf, PathBuf, &str)> = renames
+        .iter()
+        .map(|(old, new)| {
+            let hash = hashes
+                .get(old)
+                .with_context(|| format!("No hash known for {}!", old.display()))?;
+            Ok((old.clone(), new.clone(), hash.as_str()))
+        })
+        .collect::<Result<_>>()?;
+
+    while !pending.is_empty() {
+        let count_before = pending.len();
+        let mut blocked = vec![];
+
+        for (old, new, hash) in pending {
+            if root_dir.join(&new).exists() {
+                blocked.push((old, new, hash));
+            } else {
+                debug!("Moving {} to {}", old.display(), new.display());
+                move_file(root_dir, &old, &new, hash, journal)?;
+            }
+        }
+
+        // If no rename was possible, the remaining renames block each other in a cycle, which is resolved by moving one of the files to a
+        // temporary name first (with an extension that is not considered as photo, so that a file left behind by an interrupted rename is
+        // not indexed as a new photo but can be restored by undo)
+        if blocked.len() == count_before {
+            let (old, new, hash) = blocked.remove(0);
+            let temp = old.with_file_name(format!(
+                "{}.po_rename",
+                old.file_name().unwrap_or_default().to_string_lossy()
+            ));
+            debug!("Moving {} to temporary name {}", old.display(), temp.display());
+            move_file(root_dir, &old, &temp, hash, journal)?;
+            blocked.push((temp, new, hash));
+        }
+
+        pending = blocked;
+    }
+
+    Ok(())
+}
+
+/// Moves a file (paths relative to the root directory), recording the move in the given journal before actually moving the file.
+fn move_file(root_dir: &Path, from: &Path, to: &Path, filehash: &str, journal: &mut Journal) -> Result<()> {
+    journal.record(JournalChange::Move {
+        from: from.to_owned(),
+        to: to.to_owned(),
+        filehash: filehash.to_string(),
+    })?;
+
+    fs::rename(root_dir.join(from), root_dir.join(to))
+        .with_context(|| format!("Could not move {} to {}!", from.display(), to.display()))
+}
+
+/// Determines the hashes of the given files (paths relative to the root directory) for recording them in the journal. The hashes are taken
+/// from the index if a file seems unchanged since being indexed and calculated otherwise.
+fn get_file_hashes<'a>(
+    root_dir: &Path,
+    index: &Index,
+    filepaths: impl Iterator<Item = &'a PathBuf>,
+) -> Result<HashMap<PathBuf, String>> {
+    let index_map: HashMap<&Path, &IndexEntry> = index.photos.iter().map(|p| (p.filepath.as_path(), p)).collect();
+    let mut hashes = HashMap::new();
+
+    for filepath in filepaths {
+        let (filesize, modification_time) = get_file_metadata(&root_dir.join(filepath))?;
+        let hash = match index_map.get(filepath.as_path()) {
+            Some(entry) if entry.matches_file_metadata(filesize, &modification_time) => entry.filehash.clone(),
+            _ => calc_photo_hash(&root_dir.join(filepath))?,
+        };
+        hashes.insert(filepath.clone(), hash);
+    }
+
+    Ok(hashes)
+}
+
+/// Updates the paths of the index entries of moved files (keeping all other fields, in particular the original filename).
+fn update_index_paths(index: &mut Index, moves: &[(PathBuf, PathBuf)]) {
+    let mut index_map: HashMap<PathBuf, &mut IndexEntry> =
+        index.photos.iter_mut().map(|p| (p.filepath.clone(), p)).collect();
+    for (old, new) in moves.iter() {
+        match index_map.get_mut(old) {
+            Some(entry) => {
+                entry.filepath = new.clone();
             }
-            Err(e) => {
-                warn!("Could not read EXIF data from {}: {}", path.display(), e);
+            None => {
+                warn!(
+                    "{}: Photo not indexed, run \"update\" to add it to the index.",
+                    new.display()
+                );
             }
-        };
+        }
     }
+}
 
-    // Write GPX data to file
-    // TODO: Use proper temporary file instead of hardcoded one
-    let file = File::create("/tmp/photo_locations.gpx").context("Could not open GPX file for writing!")?;
-    write(&gpx_data, &file)?;
+/// Replaces the contents of the given file atomically by writing the new contents to a temporary file in the same directory (keeping the
+/// permissions of the original file) and renaming it to the original file. The temporary file has an extension that is not considered as
+/// photo, so that it is not indexed if it is left behind by an interrupted write.
+fn replace_file_contents(filepath: &Path, data: &[u8]) -> Result<()> {
+    let temp_filepath = filepath.with_file_name(format!(
+        "{}.po_tmp",
+        filepath.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let permissions = fs::metadata(filepath)?.permissions();
+
+    let mut file =
+        File::create(&temp_filepath).with_context(|| format!("Could not write to {}!", temp_filepath.display()))?;
+    file.write_all(data)
+        .with_context(|| format!("Could not write to {}!", temp_filepath.display()))?;
     file.sync_all()?;
     drop(file);
 
-    // Invoke external tool to visualize the GPX data using a map
-    if let Some(command) = command {
-        info!("Invoking external command {}...", command);
-        Command::new(command).args(["/tmp/photo_locations.gpx"]).spawn()?;
+    fs::set_permissions(&temp_filepath, permissions)?;
+    fs::rename(&temp_filepath, filepath).with_context(|| format!("Could not replace {}!", filepath.display()))
+}
+
+/// Sets the hash of the index entry of the given file (if the file is indexed) after its contents have been changed by the tool, updating
+/// the recorded size and modification time as well.
+fn update_index_entry_hash(root_dir: &Path, index: &mut Index, filepath: &Path, filehash: String) -> Result<()> {
+    if let Some(entry) = index.photos.iter_mut().find(|p| p.filepath == filepath) {
+        let (filesize, modification_time) = get_file_metadata(&root_dir.join(filepath))?;
+        index
+            .user_config
+            .replace_time_correction_filehash(&entry.filehash, &filehash);
+        entry.filehash = filehash;
+        entry.filesize = Some(filesize);
+        entry.modification_time = Some(modification_time);
     }
 
     Ok(())
 }
 
-/// Renames the files in the given directory (and potentially subdirectories) to follow the naming scheme configured in the index. Returns
-/// how many files have been renamed by the function.
+/// Renames the photos (together with their companions) in the given directory (and potentially subdirectories) to follow the naming scheme
+/// configured in the index and updates the paths of the corresponding index entries accordingly (also in dry-run mode, so the changes to
+/// the index can be previewed). Returns how many photos have been renamed by the function.
 pub fn rename(
     root_dir: &Path,
     subdir: &Path,
-    index: &Index,
+    index: &mut Index,
     photos: &[Photo],
     recursive: bool,
     dry_run: bool,
 ) -> Result<usize> {
     // TODO: Maybe ask for additional confirmation? (if not in dry-run mode)
     let cur_photos = get_photos_in_subdir(photos, subdir, recursive);
+    let mut journal = Journal::begin(root_dir, "rename");
+
+    rename_to_canonical_names(root_dir, index, cur_photos, None, dry_run, &mut journal)
+}
+
+/// Renames the given photos (which have to include all photos of the affected directories for the disambiguation of colliding names to
+/// work) and their companions to their canonical names, recording the moves in the given journal and updating the paths of the
+/// corresponding index entries. If only is given (a flag for each of the photos), just the flagged photos and the photos whose names
+/// collide with them are renamed. Returns how many photos have been renamed by the function.
+fn rename_to_canonical_names(
+    root_dir: &Path,
+    index: &mut Index,
+    photos: Vec<Photo>,
+    only: Option<&[bool]>,
+    dry_run: bool,
+    journal: &mut Journal,
+) -> Result<usize> {
+    let filepaths: Vec<PathBuf> = photos.iter().map(|p| p.relative_path.clone()).collect();
+    let (canonical_names, collisions) = get_canonical_photo_filenames_with_collisions(root_dir, &filepaths, index);
 
-    // Check for each file whether it should be renamed
-    let mut renamed_photo_count = 0;
-    for filepath in cur_photos.into_iter().map(|p| p.relative_path) {
-        let full_old_path = root_dir.join(&filepath);
+    // Restrict renaming to the selected photos and the photos colliding with them (if a selection is given)
+    let selected: Vec<bool> = match only {
+        Some(only) => {
+            let mut selected = only.to_vec();
+            for group in collisions.iter().filter(|group| group.iter().any(|i| only[*i])) {
+                for i in group {
+                    selected[*i] = true;
+                }
+            }
+            selected
+        }
+        None => vec![true; photos.len()],
+    };
 
-        match get_canonical_photo_filename(&full_old_path, &index.user_config) {
+    // Check for each photo whether it (or any of its companions) should be renamed
+    let mut renames = vec![];
+    for ((photo, canonical_name), _) in photos
+        .iter()
+        .zip(canonical_names)
+        .zip(selected)
+        .filter(|(_, selected)| *selected)
+    {
+        match canonical_name {
             Ok(canonical_name) => {
-                let cur_name = filepath
-                    .file_name()
-                    .ok_or(anyhow!("Could not file component of photo path!"))?;
-                let canonical_name = PathBuf::from(&canonical_name);
-
-                // Rename is necessary if a photo does not already have its canonical name
-                if cur_name == canonical_name {
-                    debug!("{}: Rename not necessary", filepath.display());
-                } else if dry_run {
-                    info!(
-                        "{}: Would rename file to {} (running in dry-run mode)",
-                        filepath.display(),
-                        canonical_name.display()
-                    );
+                let group: Vec<(PathBuf, PathBuf)> = photo
+                    .files()
+                    .map(|filepath| {
+                        let new_name = if *filepath == photo.relative_path {
+                            canonical_name.clone()
+                        } else {
+                            get_companion_filename(&photo.relative_path, filepath, &canonical_name)
+                        };
+                        (filepath.clone(), filepath.with_file_name(new_name))
+                    })
+                    .filter(|(old, new)| old != new)
+                    .collect();
+
+                // Rename is necessary if a photo or any of its companions does not already have its canonical name
+                if group.is_empty() {
+                    debug!("{}: Rename not necessary", photo.relative_path.display());
                 } else {
-                    info!("{}: Renaming file to {}", filepath.display(), canonical_name.display());
-
-                    let full_new_path = root_dir
-                        .join(
-                            filepath
-                                .parent()
-                                .expect("Could not get directory component of photo path!"),
-                        )
-                        .join(canonical_name);
-
-                    // Check if file already exists and refuse to overwrite already existing file
-                    // Note: Since we just check before rename here, this is not free of race conditions (good enough for now though)
-                    // See: https://internals.rust-lang.org/t/rename-file-without-overriding-existing-target/17637
-                    if full_new_path.exists() {
-                        error!("Cannot rename: Target already exists.");
-                        continue;
-                    }
-
-                    fs::rename(full_old_path, full_new_path)?;
-                    renamed_photo_count += 1;
+                    renames.push(group);
                 }
             }
             Err(e) => {
-                warn!("{}: Could not process file - {}", filepath.display(), e);
+                warn!("{}: Could not process file - {}", photo.relative_path.display(), e);
             }
         }
     }
 
+    let renames = filter_blocked_renames(root_dir, renames);
+    let renamed_photo_count = renames.len();
+    let renames: Vec<(PathBuf, PathBuf)> = renames.into_iter().flatten().collect();
+
+    for (old, new) in renames.iter() {
+        if dry_run {
+            info!(
+                "{}: Would rename file to {} (running in dry-run mode)",
+                old.display(),
+                new.file_name().unwrap_or_default().to_string_lossy()
+            );
+        } else {
+            info!(
+                "{}: Renaming file to {}",
+                old.display(),
+                new.file_name().unwrap_or_default().to_string_lossy()
+            );
+        }
+    }
+
+    if !dry_run && !renames.is_empty() {
+        let hashes = get_file_hashes(root_dir, index, renames.iter().map(|(old, _)| old))?;
+        perform_renames(root_dir, &renames, &hashes, journal)?;
+    }
+
+    update_index_paths(index, &renames);
+
     Ok(renamed_photo_count)
 }
 
-/// Creates a thumbnail catalogue in a HTML file (see description of thumbcat CLI command).
+/// Number of days within which a verification counts as recent in the coverage summary of scrub
+pub const SCRUB_RECENT_DAYS: i64 = 90;
+
+/// Result of a scrub run, including the verification coverage of the whole index after the run.
+#[derive(Serialize)]
+pub struct ScrubReport {
+    pub verified_files: usize,
+    pub verified_bytes: u64,
+
+    /// Files whose hash did not match the recorded one or that could not be read
+    pub failures: Vec<ScrubFailure>,
+
+    pub total_files: usize,
+    pub never_verified_files: usize,
+    pub oldest_verification: Option<DateTime<Utc>>,
+
+    /// Percentage of the files in the index that have been verified within the last SCRUB_RECENT_DAYS days
+    pub recently_verified_percent: f64,
+}
+
+#[derive(Serialize)]
+pub struct ScrubFailure {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+/// Parses a byte size like "500M", "20G" or "1.5TiB" (using binary prefixes) and returns it in bytes.
+fn parse_byte_size(s: &str) -> Result<u64> {
+    let re = Regex::new(r"^(?i)(\d+(?:\.\d+)?)\s*([kmgt]?)(?:i?b)?$").unwrap();
+    let cap = re
+        .captures(s.trim())
+        .with_context(|| format!("Invalid byte size: {}", s))?;
+    let value: f64 = cap[1].parse()?;
+    let exponent = match cap[2].to_lowercase().as_str() {
+        "k" => 1,
+        "m" => 2,
+        "g" => 3,
+        "t" => 4,
+        _ => 0,
+    };
+
+    Ok((value * 1024f64.powi(exponent)) as u64)
+}
+
+/// Verifies the hashes of the photos that have not been verified for the longest time (see description of scrub CLI command), recording
+/// the time of the verification in the index. The run is limited to the given number of files and bytes (selected before hashing) and to
+/// the given duration (no further files are hashed afterwards), verifying all photos if no limit is given.
+pub fn scrub(
+    root_dir: &Path,
+    index: &mut Index,
+    max_files: Option<usize>,
+    max_bytes: Option<&str>,
+    max_duration: Option<&str>,
+) -> Result<ScrubReport> {
+    let max_bytes = max_bytes.map(parse_byte_size).transpose()?;
+    let max_duration = match max_duration.map(parse_time_offset).transpose()? {
+        Some(seconds) if seconds < 0 => bail!("Maximum duration must not be negative!"),
+        Some(seconds) => Some(std::time::Duration::from_secs(seconds as u64)),
+        None => None,
+    };
+    let start = std::time::Instant::now();
+
+    // Select the photos verified least recently (never verified photos first) within the file and byte limits, always including at least
+    // one photo so that repeated runs make progress even if a single photo exceeds the byte limit
+    let mut order: Vec<usize> = (0..index.photos.len()).collect();
+    order.sort_by_key(|i| (index.photos[*i].last_verified, index.photos[*i].filepath.clone()));
+
+    let mut selected = vec![];
+    let mut selected_bytes = 0;
+    for i in order {
+        // Entries without recorded size (written by older versions) count with the current size of the file against the byte limit
+        let entry = &index.photos[i];
+        let filesize = entry.filesize.unwrap_or_else(|| {
+            fs::metadata(root_dir.join(&entry.filepath))
+                .map(|m| m.len())
+                .unwrap_or(0)
+        });
+        if max_files.is_some_and(|m| selected.len() >= m)
+            || (!selected.is_empty() && max_bytes.is_some_and(|m| selected_bytes + filesize > m))
+        {
+            break;
+        }
+
+        selected.push((i, filesize));
+        selected_bytes += filesize;
+    }
+
+    let filepaths: Vec<PathBuf> = selected
+        .iter()
+        .map(|(i, _)| index.photos[*i].filepath.clone())
+        .collect();
+    let hashes = match max_duration {
+        Some(max_duration) => calc_photo_hashes_until(root_dir, &filepaths, start + max_duration),
+        None => calc_photo_hashes(root_dir, &filepaths).into_iter().map(Some).collect(),
+    };
+
+    let now = Utc::now();
+    let mut verified_files = 0;
+    let mut verified_bytes = 0;
+    let mut failures = vec![];
+
+    for ((i, filesize), hash) in selected.into_iter().zip(hashes) {
+        let entry = &mut index.photos[i];
+        let message = match hash {
+            None => continue,
+            Some(Ok(hash)) if hash == entry.filehash => {
+                entry.last_verified = Some(now);
+                verified_files += 1;
+                verified_bytes += filesize;
+                continue;
+            }
+            Some(Ok(hash)) => format!("Hash does not match (recorded {} but was {})!", entry.filehash, hash),
+            Some(Err(e)) => format!("Could not re-hash the file: {:#}", e),
+        };
+
+        failures.push(ScrubFailure {
+            path: entry.filepath.clone(),
+            message,
+        });
+    }
+
+    // Summarize the verification coverage of the whole index
+    let recent = now - chrono::Duration::days(SCRUB_RECENT_DAYS);
+    let recently_verified_files = index
+        .photos
+        .iter()
+        .filter(|e| e.last_verified.is_some_and(|t| t >= recent))
+        .count();
+
+    Ok(ScrubReport {
+        verified_files,
+        verified_bytes,
+        failures,
+        total_files: index.photos.len(),
+        never_verified_files: index.photos.iter().filter(|e| e.last_verified.is_none()).count(),
+        oldest_verification: index.photos.iter().filter_map(|e| e.last_verified).min(),
+        recently_verified_percent: if index.photos.is_empty() {
+            100.0
+        } else {
+            recently_verified_files as f64 * 100.0 / index.photos.len() as f64
+        },
+    })
+}
+
+/// Creates a thumbnail catalogue in a HTML file (see description of thumbcat CLI command). Photos that cannot be decoded are shown without
+/// thumbnail in the catalogue and are reported in a summary at the end, grouped by the reason.
 pub fn thumbcat(
     root_dir: &Path,
     subdir: &Path,
@@ -213,25 +1676,78 @@ pub fn thumbcat(
     recursive: bool,
     resize_width: u32,
 ) -> Result<()> {
+    let ignore_rules = IgnoreRules::new(root_dir);
+    let undecodable_photos = create_thumbnail_catalogues(
+        root_dir,
+        subdir,
+        photos,
+        &ignore_rules,
+        output_filename,
+        force,
+        recursive,
+        resize_width,
+    )?;
+
+    if !undecodable_photos.is_empty() {
+        let mut photos_by_reason: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        for (path, reason) in undecodable_photos {
+            photos_by_reason.entry(reason).or_default().push(path);
+        }
+
+        warn!(
+            "{} photos could not be decoded and are shown without thumbnail:",
+            photos_by_reason.values().map(|p| p.len()).sum::<usize>()
+        );
+        for (reason, paths) in photos_by_reason {
+            warn!("  {} ({} photos):", reason, paths.len());
+            for path in paths {
+                warn!("    {}", path.display());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates the thumbnail catalogue for the given directory (and its subdirectories in recursive mode). Returns the paths of the photos that
+/// could not be decoded together with the reason. Subdirectories excluded by the ignore rules are skipped.
+#[allow(clippy::too_many_arguments)]
+fn create_thumbnail_catalogues(
+    root_dir: &Path,
+    subdir: &Path,
+    photos: &Vec<Photo>,
+    ignore_rules: &IgnoreRules,
+    output_filename: &str,
+    force: bool,
+    recursive: bool,
+    resize_width: u32,
+) -> Result<Vec<(PathBuf, String)>> {
     let root_plus_sub_dir = root_dir.join(subdir);
+    let mut undecodable_photos = vec![];
 
     // When running in recursive mode, recurse into subdirectories (sorted) before processing this one
     if recursive {
-        let mut recurse_subdirs: Vec<PathBuf> = read_dir(&root_plus_sub_dir)?
-            .filter_map(|e| {
-                let e = e.unwrap().file_name();
-                if root_plus_sub_dir.join(&e).is_dir() {
-                    Some(subdir.join(e))
-                } else {
-                    None
-                }
-            })
-            .collect();
+        let mut recurse_subdirs = vec![];
+        for e in read_dir(&root_plus_sub_dir)? {
+            let e = e?.file_name();
+            if root_plus_sub_dir.join(&e).is_dir() && !ignore_rules.is_ignored(&subdir.join(&e), true)? {
+                recurse_subdirs.push(subdir.join(e));
+            }
+        }
 
         recurse_subdirs.sort_unstable();
 
         for d in recurse_subdirs {
-            thumbcat(root_dir, &d, photos, output_filename, force, recursive, resize_width)?;
+            undecodable_photos.extend(create_thumbnail_catalogues(
+                root_dir,
+                &d,
+                photos,
+                ignore_rules,
+                output_filename,
+                force,
+                recursive,
+                resize_width,
+            )?);
         }
     }
 
@@ -259,13 +1775,13 @@ pub fn thumbcat(
                     .to_string_lossy()
                     .to_string()
             })
-            .eq(cur_tc_entries.into_iter());
+            .eq(cur_tc_entries);
         if tc_up_to_date {
             info!(
                 "Thumbnail catalogue in {} seems up-to-date, skipping directory.",
                 html_path.display()
             );
-            return Ok(());
+            return Ok(undecodable_photos);
         }
     }
 
@@ -275,7 +1791,7 @@ pub fn thumbcat(
             "No photos found in {}, skipping directory.",
             root_plus_sub_dir.display()
         );
-        return Ok(());
+        return Ok(undecodable_photos);
     }
 
     info!("Creating thumbnail catalogue in {}...", root_plus_sub_dir.display());
@@ -311,14 +1827,11 @@ pub fn thumbcat(
 
         match data {
             Ok(bytes) => {
-                writeln!(
-                    &mut f,
-                    "<p><img src=\"data:image/jpeg;base64,{}\" style=\"width: 100%\" /></p>",
-                    STANDARD_NO_PAD.encode(&bytes)
-                )?;
+                writeln!(&mut f, "<p>{}</p>", thumbnail_img_element(&bytes))?;
             }
             Err(e) => {
                 writeln!(&mut f, "<p>{}</p>", encode_safe(&e.to_string()))?;
+                undecodable_photos.push((subdir.join(photo_path), e.root_cause().to_string()));
             }
         }
     }
@@ -328,15 +1841,398 @@ pub fn thumbcat(
 
     info!("File {} generated successfully.", html_path.display());
 
-    Ok(())
+    Ok(undecodable_photos)
+}
+
+/// Returns a HTML img element showing the given thumbnail (JPEG data, see Photo::get_thumbnail()) at the width of its container.
+fn thumbnail_img_element(thumbnail: &[u8]) -> String {
+    format!(
+        "<img src=\"data:image/jpeg;base64,{}\" style=\"width: 100%\" />",
+        STANDARD_NO_PAD.encode(thumbnail)
+    )
+}
+
+/// Parses a time offset like "+1h30m", "-45s", "2d" or "-01:30:00" and returns it in seconds.
+fn parse_time_offset(s: &str) -> Result<i64> {
+    let s = s.trim();
+    let (sign, rest) = match s.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, s.strip_prefix('+').unwrap_or(s)),
+    };
+
+    let seconds = if rest.contains(':') {
+        // Format HH:MM[:SS]
+        let parts: Vec<i64> = rest
+            .split(':')
+            .map(|p| p.parse::<i64>())
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("Invalid time offset: {}", s))?;
+        match parts.as_slice() {
+            [hours, minutes] => hours * 3600 + minutes * 60,
+            [hours, minutes, seconds] => hours * 3600 + minutes * 60 + seconds,
+            _ => bail!("Invalid time offset: {}", s),
+        }
+    } else {
+        // Format with units, e.g., 1h30m
+        let re = Regex::new(r"^(?:(\d+)d)?(?:(\d+)h)?(?:(\d+)m)?(?:(\d+)s)?$").unwrap();
+        let cap = re
+            .captures(rest)
+            .filter(|_| !rest.is_empty())
+            .with_context(|| format!("Invalid time offset: {}", s))?;
+        [86400, 3600, 60, 1]
+            .iter()
+            .enumerate()
+            .map(|(i, factor)| {
+                cap.get(i + 1)
+                    .map(|m| m.as_str().parse::<i64>().unwrap_or(0))
+                    .unwrap_or(0)
+                    * factor
+            })
+            .sum()
+    };
+
+    Ok(sign * seconds)
+}
+
+/// Adds a correction for a camera clock to the index (see description of timeshift CLI command). Without a given make or model, the
+/// correction applies to all indexed photos within the given subdirectory, which are identified by their hashes (so that the correction
+/// still applies after moving them). Returns whether the index has been changed by the function.
+pub fn timeshift(
+    root_dir: &Path,
+    subdir: &Path,
+    index: &mut Index,
+    photos: &[Photo],
+    offset: &str,
+    make: Option<&str>,
+    model: Option<&str>,
+) -> Result<bool> {
+    let offset_seconds = parse_time_offset(offset)?;
+    if offset_seconds == 0 {
+        info!("Time offset is zero, nothing to do.");
+        return Ok(false);
+    }
+
+    let index_map: HashMap<&Path, &IndexEntry> = index.photos.iter().map(|p| (p.filepath.as_path(), p)).collect();
+
+    // Without a camera, the correction is tied to the indexed photos within the subdirectory by their hashes
+    let (affected_photos, filehashes) = if make.is_none() && model.is_none() {
+        let affected_photos = get_photos_in_subdir(photos, subdir, true);
+        let mut filehashes = vec![];
+        for photo in &affected_photos {
+            match index_map.get(photo.relative_path.as_path()) {
+                Some(entry) => filehashes.push(entry.filehash.clone()),
+                None => warn!(
+                    "{}: Photo not indexed, run \"update\" to add it to the index (time correction does not apply to it).",
+                    photo.relative_path.display()
+                ),
+            }
+        }
+        filehashes.sort();
+        filehashes.dedup();
+
+        if filehashes.is_empty() {
+            warn!("No indexed photos found, time correction has not been added.");
+            return Ok(false);
+        }
+
+        (affected_photos, Some(filehashes))
+    } else {
+        (photos.to_vec(), None)
+    };
+
+    let correction = TimeCorrection {
+        make: make.map(String::from),
+        model: model.map(String::from),
+        filehashes,
+        offset_seconds,
+    };
+
+    // Show the effect of the correction on all affected photos
+    let affected_hashes: Vec<Option<&str>> = affected_photos
+        .iter()
+        .map(|p| index_map.get(p.relative_path.as_path()).map(|e| e.filehash.as_str()))
+        .collect();
+    let metadata: Vec<_> = affected_photos
+        .par_iter()
+        .zip(affected_hashes.par_iter())
+        .map(|(p, filehash)| read_photo_metadata(root_dir, &p.relative_path, *filehash, &index.user_config))
+        .collect();
+
+    let mut affected_photo_count = 0;
+    for ((photo, filehash), pmd) in affected_photos.iter().zip(&affected_hashes).zip(metadata) {
+        let Ok(pmd) = pmd else {
+            continue;
+        };
+
+        if let Some(ts) = pmd.timestamp {
+            if correction.matches(*filehash, pmd.make.as_deref(), pmd.model.as_deref()) {
+                let mut new_ts = ts;
+                new_ts.local += chrono::Duration::seconds(offset_seconds);
+                info!("{}: {} -> {}", photo.relative_path.display(), ts, new_ts);
+                affected_photo_count += 1;
+            }
+        }
+    }
+
+    if affected_photo_count == 0 {
+        warn!("No photos with a timestamp are affected by the time correction.");
+    }
+
+    // Merge correction into an existing one with the same selector (removing it if the offsets cancel each other out)
+    let time_corrections = &mut index.user_config.time_corrections;
+    match time_corrections.iter().position(|c| c.has_same_selector(&correction)) {
+        Some(i) => {
+            time_corrections[i].offset_seconds += offset_seconds;
+            if time_corrections[i].offset_seconds == 0 {
+                time_corrections.remove(i);
+            }
+        }
+        None => time_corrections.push(correction),
+    }
+
+    info!(
+        "Time correction of {} seconds applies to {} photos. Run \"rename\" to apply it to the filenames.",
+        offset_seconds, affected_photo_count
+    );
+
+    Ok(true)
+}
+
+/// Reverts the last operation recorded in the journal, moving all files back to their original location, restoring changed file contents
+/// and removing created files after verifying their hashes, and updating the index accordingly. The operation is removed from the journal
+/// if it has been reverted completely. Reverting an interrupted operation or resuming an interrupted undo is possible as well, since
+/// changes that have not been done (or have already been reverted) are skipped. If reverting a change fails, the index is written with the
+/// changes reverted so far before returning the error. Returns whether any changes have been reverted.
+pub fn undo(root_dir: &Path, index: &mut Index, dry_run: bool) -> Result<bool> {
+    let mut operations = read_journal(root_dir)?;
+    let Some(operation) = operations.pop() else {
+        info!("Journal is empty, nothing to undo.");
+        return Ok(false);
+    };
+
+    info!(
+        "Undoing operation \"{}\" from {}...",
+        operation.command,
+        operation.timestamp.with_timezone(&Local).format("%d.%m.%Y %H:%M:%S")
+    );
+
+    let mut reverted_change_found = false;
+
+    for change in operation.changes.iter().rev() {
+        match revert_change(root_dir, index, change, dry_run) {
+            Ok(reverted) => reverted_change_found |= reverted,
+            Err(e) => {
+                // Keep the changes reverted so far, so that the index matches the files (the operation stays in the journal, so that the
+                // undo can be resumed)
+                if !dry_run && reverted_change_found {
+                    write_index_file(root_dir, index)?;
+                }
+                return Err(e.context("Undo aborted! Run undo again to resume it."));
+            }
+        }
+    }
+
+    if !dry_run {
+        write_journal(root_dir, &operations)?;
+    }
+
+    Ok(reverted_change_found)
+}
+
+/// Reverts the given change recorded in the journal and updates the index accordingly. Returns whether the change has been reverted
+/// (false if it has not been done or has already been reverted).
+fn revert_change(root_dir: &Path, index: &mut Index, change: &JournalChange, dry_run: bool) -> Result<bool> {
+    match change {
+        JournalChange::Move { from, to, filehash } => {
+            let full_from = root_dir.join(from);
+            let full_to = root_dir.join(to);
+
+            if !full_to.exists() && full_from.exists() {
+                debug!(
+                    "{}: Move has not been done or has already been reverted",
+                    from.display()
+                );
+                return Ok(false);
+            }
+
+            if !full_to.exists() {
+                bail!(
+                    "Cannot move {} back to {}: File does not exist anymore!",
+                    to.display(),
+                    from.display()
+                );
+            }
+
+            if full_from.exists() {
+                bail!(
+                    "Cannot move {} back to {}: Target already exists!",
+                    to.display(),
+                    from.display()
+                );
+            }
+
+            let actual_hash = calc_photo_hash(&full_to)?;
+            if actual_hash != *filehash {
+                bail!(
+                    "Cannot move {} back to {}: File has been changed (recorded hash {} but was {})!",
+                    to.display(),
+                    from.display(),
+                    filehash,
+                    actual_hash
+                );
+            }
+
+            if dry_run {
+                info!(
+                    "{}: Would move file back to {} (running in dry-run mode)",
+                    to.display(),
+                    from.display()
+                );
+            } else {
+                info!("{}: Moving file back to {}", to.display(), from.display());
+                if let Some(parent) = full_from.parent() {
+                    fs::create_dir_all(parent)
+                        .with_context(|| format!("Could not create directory {}!", parent.display()))?;
+                }
+                fs::rename(&full_to, &full_from)
+                    .with_context(|| format!("Could not move {} to {}!", to.display(), from.display()))?;
+            }
+
+            if let Some(entry) = index.photos.iter_mut().find(|p| p.filepath == *to) {
+                entry.filepath = from.clone();
+            }
+
+            Ok(true)
+        }
+        JournalChange::Create { path, filehash } => {
+            let full_path = root_dir.join(path);
+
+            if !full_path.exists() {
+                debug!(
+                    "{}: File has not been created or has already been removed",
+                    path.display()
+                );
+                return Ok(false);
+            }
+
+            let actual_hash = calc_photo_hash(&full_path)?;
+            if actual_hash != *filehash {
+                bail!(
+                    "Cannot remove {}: File has been changed (recorded hash {} but was {})!",
+                    path.display(),
+                    filehash,
+                    actual_hash
+                );
+            }
+
+            if dry_run {
+                info!("{}: Would remove file (running in dry-run mode)", path.display());
+            } else {
+                info!("{}: Removing file", path.display());
+                fs::remove_file(&full_path).with_context(|| format!("Could not remove {}!", path.display()))?;
+            }
+
+            index.photos.retain(|p| p.filepath != *path);
+            Ok(true)
+        }
+        JournalChange::ReplaceBytes {
+            path,
+            offset,
+            new_length,
+            original_bytes,
+            original_hash,
+            new_hash,
+        } => {
+            let full_path = root_dir.join(path);
+            let actual_hash = calc_photo_hash(&full_path)?;
+
+            if actual_hash == *original_hash {
+                debug!(
+                    "{}: Change has not been done or has already been reverted",
+                    path.display()
+                );
+                return Ok(false);
+            }
+
+            if actual_hash != *new_hash {
+                bail!(
+                    "Cannot restore original contents of {}: File has been changed (recorded hash {} but was {})!",
+                    path.display(),
+                    new_hash,
+                    actual_hash
+                );
+            }
+
+            let mut data = fs::read(&full_path).with_context(|| format!("Could not read {}!", full_path.display()))?;
+            let range = *offset as usize..(*offset + *new_length) as usize;
+            if range.end > data.len() {
+                bail!(
+                    "Cannot restore original contents of {}: Invalid journal entry!",
+                    path.display()
+                );
+            }
+            data.splice(range, STANDARD.decode(original_bytes)?);
+
+            if calc_data_hash(&data) != *original_hash {
+                bail!(
+                    "Cannot restore original contents of {}: Restored contents do not match the recorded hash!",
+                    path.display()
+                );
+            }
+
+            if dry_run {
+                info!(
+                    "{}: Would restore original contents (running in dry-run mode)",
+                    path.display()
+                );
+            } else {
+                info!("{}: Restoring original contents", path.display());
+                replace_file_contents(&full_path, &data)?;
+                update_index_entry_hash(root_dir, index, path, original_hash.clone())?;
+            }
+
+            Ok(true)
+        }
+    }
+}
+
+/// Changes of the index made by update().
+#[derive(Default, Serialize)]
+pub struct IndexChanges {
+    pub added: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub renamed: Vec<RenamedPath>,
+    pub deleted: Vec<PathBuf>,
+
+    /// Whether the recorded size or modification time of any unmodified photo has been updated
+    #[serde(skip)]
+    pub file_metadata_updated: bool,
 }
 
-/// Updates the index entries with the actual stored photos, detecting new, renamed and deleted photos. Returns whether the index has been
-/// changed by the function.
-pub fn update(root_dir: &Path, index: &mut Index, photos: &[Photo]) -> Result<bool> {
+#[derive(Serialize)]
+pub struct RenamedPath {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl IndexChanges {
+    /// Returns whether the index has been changed.
+    pub fn has_changes(&self) -> bool {
+        !self.added.is_empty()
+            || !self.modified.is_empty()
+            || !self.renamed.is_empty()
+            || !self.deleted.is_empty()
+            || self.file_metadata_updated
+    }
+}
+
+/// Updates the index entries with the actual stored photos, detecting new, modified, renamed and deleted photos. Photos whose size and
+/// modification time match the ones recorded in the index are assumed to be unchanged and are not re-hashed, unless rehash is set. Index
+/// entries of photos below dangling symbolic links are kept unchanged. Returns the changes made to the index.
+pub fn update(root_dir: &Path, index: &mut Index, photos: &[Photo], rehash: bool) -> Result<IndexChanges> {
     // Create index data structures for faster matching of index and photos
     let index_set: HashSet<PathBuf> = index.photos.iter().map(|p| p.filepath.clone()).collect();
-    let photos_set: HashSet<PathBuf> = photos.iter().map(|p| p.relative_path.clone()).collect();
+    let photos_set: HashSet<PathBuf> = photos.iter().flat_map(|p| p.files().cloned()).collect();
 
     // Check for photos in the index that do no longer exist and thus have been deleted or renamed
     let deleted_photos_paths: HashSet<_> = index_set.difference(&photos_set).collect();
@@ -348,49 +2244,192 @@ pub fn update(root_dir: &Path, index: &mut Index, photos: &[Photo]) -> Result<bo
         .collect();
     index.photos.retain_mut(|p| !deleted_photos_paths.contains(&p.filepath));
 
-    // Check for new photos that are not part of the index yet
-    let added_photos_paths = photos_set.difference(&index_set);
-    let mut new_photo_found = false;
+    // Photos below dangling symbolic links (e.g., pointing to a disk that is not mounted) are unavailable rather than deleted, so their
+    // index entries are kept unchanged instead of losing their recorded hashes
+    let mut unavailable_photos = vec![];
+    if !deleted_photos.is_empty() {
+        for symlink in find_dangling_symlinks(&index.user_config, root_dir)? {
+            let (unavailable, remaining): (Vec<_>, Vec<_>) = deleted_photos
+                .into_iter()
+                .partition(|p| p.filepath.starts_with(&symlink));
+            if !unavailable.is_empty() {
+                warn!(
+                    "{}: Symbolic link is dangling, keeping the index entries of the {} photos below it unchanged!",
+                    symlink.display(),
+                    unavailable.len()
+                );
+            }
+            unavailable_photos.extend(unavailable);
+            deleted_photos = remaining;
+        }
+    }
 
-    for added_photo in added_photos_paths {
-        new_photo_found = true;
+    // Read size and modification time of the remaining indexed photos and of the photos that are not part of the index yet
+    let index_metadata: Vec<(u64, DateTime<Utc>)> = index
+        .photos
+        .par_iter()
+        .map(|e| get_file_metadata(&root_dir.join(&e.filepath)))
+        .collect::<Result<_>>()?;
 
-        // Hash photo
-        let hash = calc_photo_hash(&root_dir.join(added_photo))?;
+    let mut added_photos_paths: Vec<&PathBuf> = photos_set.difference(&index_set).collect();
+    added_photos_paths.sort_unstable();
+    let added_metadata: Vec<(u64, DateTime<Utc>)> = added_photos_paths
+        .par_iter()
+        .map(|p| get_file_metadata(&root_dir.join(p)))
+        .collect::<Result<_>>()?;
 
-        let new_index_entry = if let Some(renamed_photo_index) = deleted_photos.iter().position(|p| p.filehash == hash)
-        {
-            // Remove entry in deleted_photos so it does not show up when we are logging all deleted photos below
-            let renamed_photo = deleted_photos.swap_remove(renamed_photo_index);
+    // Determine which photos need to be hashed: Indexed photos whose size or modification time has changed, and added photos unless they
+    // match exactly one of the deleted photos by size and modification time (since a renamed photo keeps both). If a rehash is forced, all
+    // of them are hashed. Hashing runs in parallel while the results are evaluated sequentially below to keep the output deterministic.
+    let mut paths_to_hash: Vec<PathBuf> = index
+        .photos
+        .iter()
+        .zip(index_metadata.iter())
+        .filter(|(e, (filesize, modification_time))| rehash || !e.matches_file_metadata(*filesize, modification_time))
+        .map(|(e, _)| e.filepath.clone())
+        .collect();
+    paths_to_hash.extend(
+        added_photos_paths
+            .iter()
+            .zip(added_metadata.iter())
+            .filter(|(_, (filesize, modification_time))| {
+                rehash
+                    || deleted_photos
+                        .iter()
+                        .filter(|p| p.matches_file_metadata(*filesize, modification_time))
+                        .count()
+                        != 1
+            })
+            .map(|(p, _)| (*p).clone()),
+    );
 
-            info!(
-                "Renamed: {} -> {}",
-                renamed_photo.filepath.display(),
-                added_photo.display()
-            );
+    let mut hashes: HashMap<PathBuf, String> = paths_to_hash
+        .iter()
+        .cloned()
+        .zip(calc_photo_hashes(root_dir, &paths_to_hash))
+        .map(|(p, h)| h.map(|h| (p, h)))
+        .collect::<Result<_>>()?;
+
+    // Check remaining photos for modifications
+    let mut changes = IndexChanges::default();
+
+    for (entry, (filesize, modification_time)) in index.photos.iter_mut().zip(index_metadata) {
+        let Some(hash) = hashes.remove(&entry.filepath) else {
+            continue;
+        };
 
-            // Hash matches one of the deleted photos (this photo was just renamed)
-            let mut new_entry = renamed_photo.clone();
-            new_entry.filepath = added_photo.clone();
-            new_entry
+        if hash != entry.filehash {
+            info!("Modified: {}", entry.filepath.display());
+            index
+                .user_config
+                .replace_time_correction_filehash(&entry.filehash, &hash);
+            entry.filehash = hash;
+            entry.perceptual_hash = None;
+            changes.modified.push(entry.filepath.clone());
         } else {
-            info!("Added: {}", added_photo.display());
+            debug!(
+                "{}: Hash unchanged, updating recorded file metadata",
+                entry.filepath.display()
+            );
+            changes.file_metadata_updated = true;
 
-            // Hash not found in the deleted photos (this photo is new)
-            IndexEntry {
-                filepath: added_photo.clone(),
-                orig_filename: added_photo.file_name().unwrap_or_default().to_string_lossy().into(),
-                filehash: hash,
+            // Note: Only a matching hash verifies the file, a modified file (e.g., by bit rot detected with rehash) must not be considered
+            //       as verified
+            entry.last_verified = Some(Utc::now());
+        }
+
+        entry.filesize = Some(filesize);
+        entry.modification_time = Some(modification_time);
+    }
+
+    // Check for new photos that are not part of the index yet
+    for (added_photo, (filesize, modification_time)) in added_photos_paths.into_iter().zip(added_metadata) {
+        // Match photo to a deleted photo by its size and modification time if it has not been hashed, otherwise by its hash (if the
+        // matching deleted photo has already been claimed by another photo, the photo is hashed now as a fallback)
+        let hash = hashes.remove(added_photo);
+        let metadata_matches: Vec<usize> = deleted_photos
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.matches_file_metadata(filesize, &modification_time))
+            .map(|(i, _)| i)
+            .collect();
+        let (renamed_photo_index, hash) = match hash {
+            None if !rehash && metadata_matches.len() == 1 => (Some(metadata_matches[0]), None),
+            _ => {
+                let hash = match hash {
+                    Some(hash) => hash,
+                    None => calc_photo_hash(&root_dir.join(added_photo))?,
+                };
+                (deleted_photos.iter().position(|p| p.filehash == hash), Some(hash))
+            }
+        };
+
+        let new_index_entry = match (renamed_photo_index, hash) {
+            (Some(renamed_photo_index), hash) => {
+                // Remove entry in deleted_photos so it does not show up when we are logging all deleted photos below
+                let renamed_photo = deleted_photos.swap_remove(renamed_photo_index);
+
+                info!(
+                    "Renamed: {} -> {}",
+                    renamed_photo.filepath.display(),
+                    added_photo.display()
+                );
+                changes.renamed.push(RenamedPath {
+                    from: renamed_photo.filepath.clone(),
+                    to: added_photo.clone(),
+                });
+
+                // Photo matches one of the deleted photos (this photo was just renamed)
+                let mut new_entry = renamed_photo.clone();
+                new_entry.filepath = added_photo.clone();
+                new_entry.filesize = Some(filesize);
+                new_entry.modification_time = Some(modification_time);
+                if hash.is_some() {
+                    new_entry.last_verified = Some(Utc::now());
+                }
+                new_entry
             }
+            (None, Some(hash)) => {
+                info!("Added: {}", added_photo.display());
+                changes.added.push(added_photo.clone());
+
+                // Hash not found in the deleted photos (this photo is new)
+                IndexEntry {
+                    filepath: added_photo.clone(),
+                    orig_filename: added_photo.file_name().unwrap_or_default().to_string_lossy().into(),
+                    filehash: hash,
+                    filesize: Some(filesize),
+                    modification_time: Some(modification_time),
+                    last_verified: Some(Utc::now()),
+                    perceptual_hash: None,
+                }
+            }
+            (None, None) => unreachable!("Photo has neither been matched by its metadata nor been hashed"),
         };
 
         index.photos.push(new_index_entry);
     }
 
     // Log deleted photos (note: apparent deletions that correspond to renamed files have already been removed from the vec)
-    for dp in deleted_photos.iter() {
+    for dp in deleted_photos {
         info!("Deleted: {}", dp.filepath.display());
+        changes.deleted.push(dp.filepath);
+    }
+
+    index.photos.extend(unavailable_photos);
+
+    // Corrections tied to photos by their hashes do not apply anymore if all of these photos have been deleted
+    let indexed_hashes: HashSet<&str> = index.photos.iter().map(|p| p.filehash.as_str()).collect();
+    for correction in &index.user_config.time_corrections {
+        if let Some(hashes) = &correction.filehashes {
+            if !hashes.iter().any(|h| indexed_hashes.contains(h.as_str())) {
+                warn!(
+                    "Time correction of {} seconds does not apply to any indexed photo anymore (consider removing it from the index).",
+                    correction.offset_seconds
+                );
+            }
+        }
     }
 
-    Ok(new_photo_found || !deleted_photos.is_empty())
+    Ok(changes)
 }
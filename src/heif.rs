@@ -0,0 +1,207 @@
+use anyhow::{bail, Context, Result};
+
+use crate::bmff::{container_payload, find_box, full_box_payload, parse_boxes, read_u16, read_u32, read_u64};
+
+/// Major brands of HEIF files (HEIC images as written by iPhones, image sequences and AVIF images)
+const HEIF_BRANDS: &[&[u8; 4]] = &[
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"hevm", b"hevs", b"mif1", b"msf1", b"avif", b"avis",
+];
+
+/// Location of an item (e.g., an image or the EXIF data) within a HEIF file.
+struct ItemLocation {
+    /// Construction method (0: extents are file offsets, 1: extents are offsets within the item data box)
+    construction_method: u16,
+    extents: Vec<(u64, u64)>,
+}
+
+/// Item of a HEIF file, given by its ID, type (e.g., "hvc1" for HEVC-coded images, "jpeg" or "Exif") and location.
+struct Item {
+    id: u32,
+    item_type: [u8; 4],
+    location: Option<ItemLocation>,
+}
+
+/// Returns whether the given data (the first bytes of a file) are the start of a HEIF file, determined by the major brand of the file type
+/// box.
+pub fn is_heif(header: &[u8]) -> bool {
+    header.get(4..8) == Some(b"ftyp") && HEIF_BRANDS.iter().any(|b| header.get(8..12) == Some(&b[..]))
+}
+
+/// Returns the TIFF data of the EXIF item of the given HEIF file.
+pub fn read_heif_exif(data: &[u8]) -> Result<Vec<u8>> {
+    let (items, idat) = read_items(data)?;
+    let item = items
+        .iter()
+        .find(|i| &i.item_type == b"Exif")
+        .context("No EXIF data found in HEIF file!")?;
+    let exif = read_item_data(data, idat, item)?;
+
+    // The EXIF item starts with the offset of the TIFF header (usually skipping an "Exif\0\0" header)
+    let tiff_header_offset = read_u32(&exif, 0)? as usize;
+    Ok(exif
+        .get(4 + tiff_header_offset..)
+        .context("Invalid EXIF item in HEIF file!")?
+        .to_vec())
+}
+
+/// Returns the JPEG data of the largest JPEG-coded image item of the given HEIF file (usually a thumbnail). Images coded in other formats
+/// (like HEVC, which is used by most cameras and phones) cannot be decoded, in which case an error is returned.
+pub fn read_heif_jpeg_image(data: &[u8]) -> Result<Vec<u8>> {
+    let (items, idat) = read_items(data)?;
+    let item = items.iter().filter(|i| &i.item_type == b"jpeg").max_by_key(|i| {
+        i.location
+            .as_ref()
+            .map(|l| l.extents.iter().map(|(_, length)| length).sum::<u64>())
+            .unwrap_or(0)
+    });
+
+    match item {
+        Some(item) => read_item_data(data, idat, item),
+        None if items.iter().any(|i| &i.item_type == b"hvc1") => {
+            bail!(
+                "HEVC-coded HEIF image without JPEG-coded thumbnail cannot be decoded (decoding HEVC is not supported)"
+            )
+        }
+        None => bail!("HEIF image without JPEG-coded thumbnail cannot be decoded"),
+    }
+}
+
+/// Reads the items (with their locations) from the meta box of the given HEIF file. Returns the items and the payload of the item data box
+/// (if any).
+fn read_items(data: &[u8]) -> Result<(Vec<Item>, Option<&[u8]>)> {
+    let meta = parse_boxes(data)?
+        .into_iter()
+        .find(|b| &b.box_type == b"meta")
+        .context("No meta box found in HEIF file!")?;
+    let meta = container_payload(&meta);
+
+    let iinf = find_box(meta, &[b"iinf"])?.context("No item information box found in HEIF file!")?;
+    let (version, iinf) = full_box_payload(iinf)?;
+    let entries = iinf.get(if version == 0 { 2 } else { 4 }..).unwrap_or_default();
+
+    let mut items = vec![];
+    for infe in parse_boxes(entries)?.into_iter().filter(|b| &b.box_type == b"infe") {
+        // Note: Item information entries of version 0 and 1 do not contain the item type (and are not used for images)
+        let (version, infe) = full_box_payload(infe.payload)?;
+        let (id, type_offset) = match version {
+            2 => (read_u16(infe, 0)? as u32, 4),
+            3 => (read_u32(infe, 0)?, 6),
+            _ => continue,
+        };
+        let item_type = infe
+            .get(type_offset..type_offset + 4)
+            .context("Invalid item information entry!")?;
+        items.push(Item {
+            id,
+            item_type: [item_type[0], item_type[1], item_type[2], item_type[3]],
+            location: None,
+        });
+    }
+
+    if let Some(iloc) = find_box(meta, &[b"iloc"])? {
+        for (id, location) in read_item_locations(iloc)? {
+            if let Some(item) = items.iter_mut().find(|i| i.id == id) {
+                item.location = Some(location);
+            }
+        }
+    }
+
+    Ok((items, find_box(meta, &[b"idat"])?))
+}
+
+/// Parses the item location box, returning the locations by item ID.
+fn read_item_locations(iloc: &[u8]) -> Result<Vec<(u32, ItemLocation)>> {
+    let (version, iloc) = full_box_payload(iloc)?;
+    let sizes = read_u16(iloc, 0)?;
+    let offset_size = (sizes >> 12) as usize;
+    let length_size = ((sizes >> 8) & 0xf) as usize;
+    let base_offset_size = ((sizes >> 4) & 0xf) as usize;
+    let index_size = if version >= 1 { (sizes & 0xf) as usize } else { 0 };
+
+    let read_sized = |pos: &mut usize, size: usize| -> Result<u64> {
+        let value = match size {
+            0 => 0,
+            4 => read_u32(iloc, *pos)? as u64,
+            8 => read_u64(iloc, *pos)?,
+            _ => bail!("Invalid field size {} in item location box!", size),
+        };
+        *pos += size;
+        Ok(value)
+    };
+
+    let mut pos = 2;
+    let item_count = if version < 2 {
+        pos += 2;
+        read_u16(iloc, 2)? as u32
+    } else {
+        pos += 4;
+        read_u32(iloc, 2)?
+    };
+
+    let mut locations = vec![];
+    for _ in 0..item_count {
+        let id = if version < 2 {
+            pos += 2;
+            read_u16(iloc, pos - 2)? as u32
+        } else {
+            pos += 4;
+            read_u32(iloc, pos - 4)?
+        };
+        let construction_method = if version >= 1 {
+            pos += 2;
+            read_u16(iloc, pos - 2)? & 0xf
+        } else {
+            0
+        };
+
+        // Skip data reference index
+        pos += 2;
+        let base_offset = read_sized(&mut pos, base_offset_size)?;
+        let extent_count = read_u16(iloc, pos)?;
+        pos += 2;
+
+        let mut extents = vec![];
+        for _ in 0..extent_count {
+            read_sized(&mut pos, index_size)?;
+            let offset = read_sized(&mut pos, offset_size)?;
+            let length = read_sized(&mut pos, length_size)?;
+            let offset = base_offset
+                .checked_add(offset)
+                .context("Invalid extent offset in item location box!")?;
+            extents.push((offset, length));
+        }
+
+        locations.push((
+            id,
+            ItemLocation {
+                construction_method,
+                extents,
+            },
+        ));
+    }
+
+    Ok(locations)
+}
+
+/// Reads the data of the given item by concatenating its extents.
+fn read_item_data(data: &[u8], idat: Option<&[u8]>, item: &Item) -> Result<Vec<u8>> {
+    let location = item.location.as_ref().context("Location of HEIF item unknown!")?;
+    let source = match location.construction_method {
+        0 => data,
+        1 => idat.context("Item data box missing in HEIF file!")?,
+        method => bail!("Unsupported construction method {} of HEIF item!", method),
+    };
+
+    let mut res = vec![];
+    for (offset, length) in location.extents.iter() {
+        // Note: An extent length of 0 refers to the remaining data
+        let start = usize::try_from(*offset)?;
+        let end = match length {
+            0 => source.len(),
+            length => start.saturating_add(usize::try_from(*length)?),
+        };
+        res.extend(source.get(start..end).context("HEIF item exceeds the file!")?);
+    }
+
+    Ok(res)
+}
@@ -0,0 +1,218 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Name of the ignore files, which can be placed in any directory of a collection and contain gitignore-style patterns that are relative
+/// to the directory containing the file
+pub const IGNORE_FILE_NAME: &str = ".poignore";
+
+/// Patterns that are always ignored (in the syntax of the ignore files): Version control directories, recycle bins and thumbnail
+/// directories created by NAS systems and operating systems as well as Lightroom preview caches
+const DEFAULT_IGNORE_PATTERNS: &[&str] = &[
+    ".git/",
+    "\\#recycle/",
+    "@eaDir/",
+    "@Recycle/",
+    ".@__thumb/",
+    "$RECYCLE.BIN/",
+    ".Trash-*/",
+    ".Trashes/",
+    ".thumbnails/",
+    "System Volume Information/",
+    "*.lrdata/",
+];
+
+/// Single pattern of an ignore file.
+struct Pattern {
+    /// Regular expression that is matched against the path relative to the directory containing the ignore file (using "/" as separator)
+    regex: Regex,
+
+    /// Whether the pattern starts with "!", i.e., re-includes paths excluded by a previous pattern
+    negated: bool,
+
+    /// Whether the pattern ends with "/", i.e., matches directories only
+    dir_only: bool,
+}
+
+/// Rules for ignoring files and directories when scanning a collection, given by the built-in default patterns and the ignore files in the
+/// scanned directories. As with gitignore, the last matching pattern decides whether a path is ignored, with the patterns of ignore files
+/// in subdirectories taking precedence over those of their parent directories. The ignore files are read lazily and cached.
+pub struct IgnoreRules {
+    root_dir: PathBuf,
+    defaults: Rc<Vec<Pattern>>,
+    ignore_files: RefCell<HashMap<PathBuf, Rc<Vec<Pattern>>>>,
+}
+
+impl IgnoreRules {
+    pub fn new(root_dir: &Path) -> IgnoreRules {
+        let defaults = DEFAULT_IGNORE_PATTERNS
+            .iter()
+            .filter_map(|p| parse_pattern(p).transpose())
+            .collect::<Result<_>>()
+            .expect("Invalid default ignore pattern!");
+
+        IgnoreRules {
+            root_dir: root_dir.to_owned(),
+            defaults: Rc::new(defaults),
+            ignore_files: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether the given file or directory (path relative to the root directory) is ignored by the patterns of the built-in
+    /// defaults and the ignore files in its parent directories. Note that the parent directories themselves are not checked, so a directory
+    /// tree has to be walked top-down, skipping ignored directories.
+    pub fn is_ignored(&self, relative_path: &Path, is_dir: bool) -> Result<bool> {
+        let mut ignored = matches_patterns(&self.defaults, relative_path, is_dir).unwrap_or(false);
+
+        // Note: Ancestors are iterated from the root directory down to the parent directory of the path
+        let dirs: Vec<&Path> = relative_path.ancestors().skip(1).collect();
+        for dir in dirs.into_iter().rev() {
+            let patterns = self.read_ignore_file(dir)?;
+            let path_in_dir = relative_path.strip_prefix(dir).unwrap_or(relative_path);
+            if let Some(matched) = matches_patterns(&patterns, path_in_dir, is_dir) {
+                ignored = matched;
+            }
+        }
+
+        Ok(ignored)
+    }
+
+    /// Returns the patterns of the ignore file in the given directory (relative to the root directory), which are empty if there is none.
+    fn read_ignore_file(&self, dir: &Path) -> Result<Rc<Vec<Pattern>>> {
+        if let Some(patterns) = self.ignore_files.borrow().get(dir) {
+            return Ok(patterns.clone());
+        }
+
+        let filepath = self.root_dir.join(dir).join(IGNORE_FILE_NAME);
+        let patterns = match read_to_string(&filepath) {
+            Ok(contents) => contents
+                .lines()
+                .enumerate()
+                .filter_map(|(i, line)| {
+                    parse_pattern(line)
+                        .with_context(|| format!("Invalid pattern in line {} of {}!", i + 1, filepath.display()))
+                        .transpose()
+                })
+                .collect::<Result<_>>()?,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e).with_context(|| format!("Could not read {}!", filepath.display())),
+        };
+
+        let patterns = Rc::new(patterns);
+        self.ignore_files.borrow_mut().insert(dir.to_owned(), patterns.clone());
+        Ok(patterns)
+    }
+}
+
+/// Returns whether the last of the given patterns matching the given path excludes (true) or re-includes (false) it, or None if no pattern
+/// matches.
+fn matches_patterns(patterns: &[Pattern], path: &Path, is_dir: bool) -> Option<bool> {
+    let path = path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    patterns
+        .iter()
+        .rev()
+        .find(|p| (is_dir || !p.dir_only) && p.regex.is_match(&path))
+        .map(|p| !p.negated)
+}
+
+/// Parses a line of an ignore file, returning None for empty lines and comments.
+fn parse_pattern(line: &str) -> Result<Option<Pattern>> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let (negated, pattern) = match line.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let (dir_only, pattern) = match pattern.strip_suffix('/') {
+        Some(rest) => (true, rest),
+        None => (false, pattern),
+    };
+
+    // Patterns containing a slash (except for a trailing one) are relative to the directory of the ignore file, all other patterns match
+    // the name of a file or directory at any level
+    let anchored = pattern.contains('/');
+    let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+
+    let mut regex = String::from("^");
+    if !anchored {
+        regex.push_str("(?:.*/)?");
+    }
+    regex.push_str(&glob_to_regex(pattern));
+    regex.push('$');
+
+    Ok(Some(Pattern {
+        regex: Regex::new(&regex)?,
+        negated,
+        dir_only,
+    }))
+}
+
+/// Converts a glob pattern as used in gitignore files to a regular expression: "*" matches anything except "/", "?" matches a single
+/// character except "/", "[...]" matches a character class and "**" matches any number of directories (as "**/" prefix, "/**/" infix
+/// or "/**" suffix). A backslash escapes the following character.
+fn glob_to_regex(pattern: &str) -> String {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut regex = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                let at_start = i == 0 || chars[i - 1] == '/';
+                match chars.get(i + 2) {
+                    Some('/') if at_start => {
+                        regex.push_str("(?:.*/)?");
+                        i += 3;
+                    }
+                    None if at_start => {
+                        regex.push_str(".*");
+                        i += 2;
+                    }
+                    _ => {
+                        regex.push_str("[^/]*");
+                        i += 2;
+                    }
+                }
+                continue;
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => match chars[i + 1..].iter().position(|c| *c == ']') {
+                Some(end) => {
+                    let class: String = chars[i + 1..i + 1 + end].iter().collect();
+                    let class = match class.strip_prefix('!') {
+                        Some(rest) => format!("^{}", rest),
+                        None => class,
+                    };
+                    regex.push('[');
+                    regex.push_str(&class.replace('\\', "\\\\").replace('[', "\\["));
+                    regex.push(']');
+                    i += end + 2;
+                    continue;
+                }
+                None => regex.push_str("\\["),
+            },
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                regex.push_str(&regex::escape(&chars[i].to_string()));
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+
+    regex
+}
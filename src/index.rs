@@ -1,19 +1,265 @@
-use anyhow::{Context, Result};
-use log::debug;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, FixedOffset, Utc};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::collections::BTreeMap;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
+use std::fs::{rename, File, OpenOptions, TryLockError};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{self, Command};
 use std::str::from_utf8;
 
+use crate::checks::{check_names, CHECKS};
+use crate::naming::{validate_collision_suffix, validate_directory_scheme, validate_file_naming_scheme};
+
 const INDEX_FILE_NAME: &str = "photo_organizer_index.json";
+const INDEX_TEMP_FILE_NAME: &str = "photo_organizer_index.json.tmp";
+const LOCK_FILE_NAME: &str = "photo_organizer.lock";
+
+/// Version of the index file format written by this version of the tool. Whenever the format changes, this has to be incremented and a
+/// corresponding migration step has to be added to migrate_index().
+const INDEX_VERSION: u64 = 16;
+
+/// Time zone in which photo timestamps are expressed when determining filenames. Serialized as "local" (local time of the camera clock as
+/// stored in the EXIF data), "utc" or a fixed UTC offset like "+02:00".
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum TimeZonePolicy {
+    Local,
+    Utc,
+    Fixed(FixedOffset),
+}
+
+impl TryFrom<String> for TimeZonePolicy {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "local" => Ok(TimeZonePolicy::Local),
+            "utc" => Ok(TimeZonePolicy::Utc),
+            _ => parse_utc_offset(&s).map(TimeZonePolicy::Fixed).ok_or_else(|| {
+                format!(
+                    "Invalid time zone \"{}\" (expected \"local\", \"utc\" or an offset like \"+02:00\")",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+impl From<TimeZonePolicy> for String {
+    fn from(policy: TimeZonePolicy) -> Self {
+        match policy {
+            TimeZonePolicy::Local => "local".into(),
+            TimeZonePolicy::Utc => "utc".into(),
+            TimeZonePolicy::Fixed(offset) => offset.to_string(),
+        }
+    }
+}
+
+/// Parses an UTC offset in the format used by the EXIF OffsetTime* tags (e.g., "+02:00" or "-05:30").
+pub fn parse_utc_offset(s: &str) -> Option<FixedOffset> {
+    let (sign, rest) = match s.trim().split_at_checked(1)? {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => return None,
+    };
+    let (hours, minutes) = rest.split_once(':')?;
+    let hours: i32 = hours.parse().ok()?;
+    let minutes: i32 = minutes.parse().ok()?;
+
+    if minutes >= 60 {
+        return None;
+    }
+
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Severity of the findings of a check: Findings of checks set to "error" make the check command fail, findings of checks set to
+/// "warning" are only reported, and checks set to "ignore" are not run at all.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Ignore,
+}
+
+/// Configuration of a single check.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CheckConfig {
+    pub severity: Severity,
+
+    /// Directories (relative to the root directory) whose files (including those in subdirectories) are exempt from the check, e.g., a
+    /// directory of scans that do not follow the naming scheme
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exempt_directories: Vec<PathBuf>,
+}
+
+impl CheckConfig {
+    /// Returns whether the given file (path relative to the root directory) is exempt from the check.
+    pub fn is_exempt(&self, filepath: &Path) -> bool {
+        self.exempt_directories.iter().any(|d| filepath.starts_with(d))
+    }
+}
+
+impl Default for CheckConfig {
+    fn default() -> Self {
+        CheckConfig {
+            severity: Severity::Error,
+            exempt_directories: vec![],
+        }
+    }
+}
+
+/// Correction of a camera clock that is applied to the timestamps of all photos matching the given camera (make and/or model as stored in
+/// the EXIF data) and/or hashes. If multiple corrections match a photo, their offsets are added.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TimeCorrection {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub make: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+
+    /// Hashes of the affected photos (for corrections of the photos within a directory), so that the correction still applies after the
+    /// photos have been moved to another directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filehashes: Option<Vec<String>>,
+
+    /// Offset added to the timestamps (in seconds)
+    pub offset_seconds: i64,
+}
+
+impl TimeCorrection {
+    /// Returns whether the correction applies to a photo with the given hash (as recorded in the index) and make/model. The hash is None
+    /// for photos that are not indexed (e.g., photos outside of the collection that are imported), to which hash-specific corrections never
+    /// apply.
+    pub fn matches(&self, filehash: Option<&str>, make: Option<&str>, model: Option<&str>) -> bool {
+        self.make.as_deref().map(|m| Some(m) == make).unwrap_or(true)
+            && self.model.as_deref().map(|m| Some(m) == model).unwrap_or(true)
+            && match (self.filehashes.as_deref(), filehash) {
+                (Some(hashes), Some(h)) => hashes.iter().any(|x| x == h),
+                (Some(_), None) => false,
+                (None, _) => true,
+            }
+    }
+
+    /// Returns whether the correction has the same selection criteria (camera and hashes) as the given one.
+    pub fn has_same_selector(&self, other: &TimeCorrection) -> bool {
+        self.make == other.make && self.model == other.model && self.filehashes == other.filehashes
+    }
+}
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct UserConfig {
     pub file_naming_scheme: String,
+
+    /// Suffix inserted before the file extension to disambiguate photos in the same directory that would otherwise get the same filename
+    /// (%{counter} is replaced with the number of the photo among the colliding ones, starting at 1)
+    pub file_naming_collision_suffix: String,
+
+    /// Time zone in which the timestamps used for the filenames are expressed (using "utc" or a fixed offset makes photos from cameras set
+    /// to different time zones sort correctly, but requires the UTC offset of the photos to be known)
+    pub file_naming_time_zone: TimeZonePolicy,
+
+    /// Directory (relative to the root directory) in which photos should be stored, given as a chrono format string that is applied to the
+    /// photo timestamp (e.g., "%Y/%Y-%m"). If set, the organize command moves photos accordingly and check reports misplaced photos.
+    pub directory_naming_scheme: Option<String>,
+
+    /// Corrections for camera clocks that are applied to photo timestamps (see timeshift command)
+    pub time_corrections: Vec<TimeCorrection>,
+
+    /// Directory (relative to the root directory) into which photos are copied by the import command, given as a chrono format string
+    /// that is applied to the photo timestamp (e.g., "%Y/%Y-%m-%d")
+    pub import_directory_scheme: String,
+
     pub file_types: BTreeMap<String, Vec<String>>,
+
+    /// File types (keys of file_types) whose files are companions of a photo with the same filename stem and another file type (e.g., RAW
+    /// files stored alongside the JPEG files by the camera). Companions are moved and renamed together with their photo.
+    pub companion_file_types: Vec<String>,
+
+    /// File extensions of sidecar files (e.g., XMP files written by photo editors), which are moved and renamed together with the photo
+    /// they belong to (e.g., "IMG_1234.xmp" or "IMG_1234.CR2.xmp" for "IMG_1234.CR2")
+    pub sidecar_extensions: Vec<String>,
+
+    /// Whether symbolic links are followed when scanning the collection (e.g., for directories stored on another disk). Symbolic links
+    /// leading to a loop are skipped.
+    pub follow_symlinks: bool,
+
+    /// Whether scanning the collection stays on the file system of the root directory, i.e., skips mount points and symbolic links to
+    /// directories on other file systems
+    pub same_file_system: bool,
+
+    /// Configuration of the checks run by the check command by check name (checks without configuration are run with their default
+    /// severity, which is "error" for all checks except the expensive decodability check)
+    pub checks: BTreeMap<String, CheckConfig>,
+}
+
+impl UserConfig {
+    /// Validates the naming schemes and the photo group settings of the user config, so that configuration errors are reported once when
+    /// reading the index file instead of for every photo.
+    pub fn validate(&self) -> Result<()> {
+        validate_file_naming_scheme(&self.file_naming_scheme).context("Invalid file naming scheme!")?;
+        validate_collision_suffix(&self.file_naming_collision_suffix).context("Invalid collision suffix!")?;
+        validate_directory_scheme(&self.import_directory_scheme).context("Invalid import directory scheme!")?;
+
+        if let Some(scheme) = &self.directory_naming_scheme {
+            validate_directory_scheme(scheme).context("Invalid directory naming scheme!")?;
+        }
+
+        if let Some(file_type) = self
+            .companion_file_types
+            .iter()
+            .find(|t| !self.file_types.contains_key(*t))
+        {
+            bail!(
+                "Companion file type \"{}\" is not defined in the file types!",
+                file_type
+            );
+        }
+        let check_names = check_names();
+        if let Some(name) = self.checks.keys().find(|n| !check_names.contains(&n.as_str())) {
+            bail!(
+                "Unknown check \"{}\" configured (available checks: {})!",
+                name,
+                check_names.join(", ")
+            );
+        }
+        if let Some(dir) = self
+            .checks
+            .values()
+            .flat_map(|c| c.exempt_directories.iter())
+            .find(|d| !d.is_relative())
+        {
+            bail!(
+                "Exempt directory {} of a check must be relative to the root directory!",
+                dir.display()
+            );
+        }
+
+        if let Some(extension) = self
+            .sidecar_extensions
+            .iter()
+            .find(|e| self.file_types.values().flatten().any(|f| f == *e))
+        {
+            bail!("Sidecar extension \"{}\" is also defined as a file type!", extension);
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the given hash of a photo whose contents have been changed by its new hash in the camera clock corrections, so that
+    /// corrections tied to the photo still apply to it.
+    pub fn replace_time_correction_filehash(&mut self, old_filehash: &str, new_filehash: &str) {
+        for hashes in self.time_corrections.iter_mut().filter_map(|c| c.filehashes.as_mut()) {
+            for h in hashes.iter_mut().filter(|h| *h == old_filehash) {
+                *h = new_filehash.to_string();
+            }
+        }
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -21,10 +267,36 @@ pub struct IndexEntry {
     pub filepath: PathBuf,
     pub orig_filename: String,
     pub filehash: String,
+
+    /// File size in bytes at the time the file was last hashed (used to detect unchanged files without re-hashing them)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filesize: Option<u64>,
+
+    /// Modification time of the file at the time the file was last hashed (used like filesize)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modification_time: Option<DateTime<Utc>>,
+
+    /// Time at which the hash of the file has last been verified to match the recorded one (by scrub or when the file was hashed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_verified: Option<DateTime<Utc>>,
+
+    /// Perceptual hash of the image as hex-encoded string (used by the dupes command to find visually similar photos, which computes it
+    /// for photos that do not have one yet)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perceptual_hash: Option<String>,
+}
+
+impl IndexEntry {
+    /// Returns whether the recorded size and modification time match the given ones, i.e., whether the file can be assumed to be
+    /// unchanged since it has been hashed. Always returns false for entries without recorded size and modification time.
+    pub fn matches_file_metadata(&self, filesize: u64, modification_time: &DateTime<Utc>) -> bool {
+        self.filesize == Some(filesize) && self.modification_time.as_ref() == Some(modification_time)
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Index {
+    pub version: u64,
     pub user_config: UserConfig,
     pub photos: Vec<IndexEntry>,
 }
@@ -32,18 +304,62 @@ pub struct Index {
 impl Default for Index {
     fn default() -> Self {
         Index {
+            version: INDEX_VERSION,
             user_config: UserConfig {
                 file_naming_scheme: String::from("%Y%m%d_%H%M%S_%{type}.%{fileextension}"),
+                file_naming_collision_suffix: String::from("_%{counter}"),
+                file_naming_time_zone: TimeZonePolicy::Local,
+                directory_naming_scheme: None,
+                time_corrections: vec![],
+                import_directory_scheme: String::from("%Y/%Y-%m-%d"),
                 file_types: BTreeMap::from([
-                    ("IMG".into(), vec!["jpg".into(), "jpeg".into(), "png".into()]),
-                    ("VID".into(), vec!["mp4".into()]),
+                    (
+                        "IMG".into(),
+                        vec![
+                            "jpg".into(),
+                            "jpeg".into(),
+                            "png".into(),
+                            "heic".into(),
+                            "heif".into(),
+                            "hif".into(),
+                        ],
+                    ),
+                    ("RAW".into(), default_raw_file_extensions()),
+                    ("VID".into(), vec!["mp4".into(), "mov".into(), "m4v".into()]),
                 ]),
+                companion_file_types: vec!["RAW".into()],
+                sidecar_extensions: vec!["xmp".into()],
+                follow_symlinks: false,
+                same_file_system: false,
+                checks: default_check_configs(),
             },
             photos: vec![],
         }
     }
 }
 
+/// Returns the default configuration of all checks (listing them explicitly makes them easier to adjust in the index file).
+fn default_check_configs() -> BTreeMap<String, CheckConfig> {
+    CHECKS
+        .iter()
+        .map(|check| {
+            let config = CheckConfig {
+                severity: check.default_severity(),
+                exempt_directories: vec![],
+            };
+            (check.name().to_string(), config)
+        })
+        .collect()
+}
+
+/// Returns the file extensions of common RAW formats (Sony, Canon, Adobe/Leica/Pentax, Nikon, Olympus, Fujifilm and Panasonic).
+fn default_raw_file_extensions() -> Vec<String> {
+    ["arw", "cr2", "cr3", "dng", "nef", "orf", "raf", "rw2"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
 /// Checks whether the index file in the given root directory is put under version control using Git. Return false if Git is not installed,
 /// no Git repository has been created, the index file is not versioned (determined using git ls-files) or any other error occured.
 pub fn check_index_file_is_git_versioned(root_dir: &Path) -> bool {
@@ -76,28 +392,378 @@ pub fn get_index_root_and_subdir(dir: &Path) -> Result<Option<(PathBuf, PathBuf)
     Ok(None)
 }
 
-/// Reads the index file for given root directory.
-pub fn read_index_file(root_dir: &Path) -> Result<Index> {
+/// Migrates the given (parsed but not yet deserialized) index from the given version to the next version.
+fn migrate_index(version: u64, index: &mut Map<String, Value>) -> Result<()> {
+    match version {
+        1 => {
+            // Version 1 had no version field yet. Version 2 added the optional filesize and modification_time fields to the photo
+            // entries, which are filled by the next update, so there is nothing to migrate.
+        }
+        2 => {
+            // Version 3 added the collision suffix to the user config
+            index
+                .get_mut("user_config")
+                .and_then(|c| c.as_object_mut())
+                .context("User config missing in index file!")?
+                .insert("file_naming_collision_suffix".into(), Value::from("_%{counter}"));
+        }
+        3 => {
+            // Version 4 added the time zone policy to the user config (keeping the previous behavior of using the camera's local time)
+            index
+                .get_mut("user_config")
+                .and_then(|c| c.as_object_mut())
+                .context("User config missing in index file!")?
+                .insert("file_naming_time_zone".into(), Value::from("local"));
+        }
+        4 => {
+            // Version 5 added the camera clock corrections to the user config
+            index
+                .get_mut("user_config")
+                .and_then(|c| c.as_object_mut())
+                .context("User config missing in index file!")?
+                .insert("time_corrections".into(), Value::Array(vec![]));
+        }
+        5 => {
+            // Version 6 added the directory naming scheme for imported photos to the user config
+            index
+                .get_mut("user_config")
+                .and_then(|c| c.as_object_mut())
+                .context("User config missing in index file!")?
+                .insert("import_directory_scheme".into(), Value::from("%Y/%Y-%m-%d"));
+        }
+        6 => {
+            // Version 7 added the (optional) directory naming scheme to the user config
+            index
+                .get_mut("user_config")
+                .and_then(|c| c.as_object_mut())
+                .context("User config missing in index file!")?
+                .insert("directory_naming_scheme".into(), Value::Null);
+        }
+        7 => {
+            // Version 8 added support for MOV and M4V videos, whose file extensions are added to the VID file type (if it exists and they
+            // are not configured for another file type yet)
+            let file_types = index
+                .get_mut("user_config")
+                .and_then(|c| c.get_mut("file_types"))
+                .and_then(|t| t.as_object_mut())
+                .context("File types missing in user config!")?;
+
+            let configured_extensions: Vec<String> = file_types
+                .values()
+                .filter_map(|e| e.as_array())
+                .flatten()
+                .filter_map(|e| e.as_str())
+                .map(String::from)
+                .collect();
+            if let Some(vid_extensions) = file_types.get_mut("VID").and_then(|e| e.as_array_mut()) {
+                for extension in ["mov", "m4v"] {
+                    if !configured_extensions.iter().any(|e| e == extension) {
+                        vid_extensions.push(Value::from(extension));
+                    }
+                }
+            }
+        }
+        8 => {
+            // Version 9 added photo groups (companion files and sidecar files) to the user config. The RAW file type is added with all
+            // RAW file extensions that are not configured for another file type yet (previously, these files were not considered at all).
+            let user_config = index
+                .get_mut("user_config")
+                .and_then(|c| c.as_object_mut())
+                .context("User config missing in index file!")?;
+            let file_types = user_config
+                .get_mut("file_types")
+                .and_then(|t| t.as_object_mut())
+                .context("File types missing in user config!")?;
+
+            let configured_extensions: Vec<&str> = file_types
+                .values()
+                .filter_map(|e| e.as_array())
+                .flatten()
+                .filter_map(|e| e.as_str())
+                .collect();
+            let raw_extensions: Vec<Value> = default_raw_file_extensions()
+                .into_iter()
+                .filter(|e| !configured_extensions.contains(&e.as_str()))
+                .map(Value::from)
+                .collect();
+            if !file_types.contains_key("RAW") && !raw_extensions.is_empty() {
+                file_types.insert("RAW".into(), Value::Array(raw_extensions));
+            }
+
+            user_config.insert("companion_file_types".into(), Value::from(vec!["RAW"]));
+            user_config.insert("sidecar_extensions".into(), Value::from(vec!["xmp"]));
+        }
+        9 => {
+            // Version 10 added support for HEIF images, whose file extensions are added to the IMG file type (if it exists and they are not
+            // configured for another file type yet)
+            let file_types = index
+                .get_mut("user_config")
+                .and_then(|c| c.get_mut("file_types"))
+                .and_then(|t| t.as_object_mut())
+                .context("File types missing in user config!")?;
+
+            let configured_extensions: Vec<String> = file_types
+                .values()
+                .filter_map(|e| e.as_array())
+                .flatten()
+                .filter_map(|e| e.as_str())
+                .map(String::from)
+                .collect();
+            if let Some(img_extensions) = file_types.get_mut("IMG").and_then(|e| e.as_array_mut()) {
+                for extension in ["heic", "heif", "hif"] {
+                    if !configured_extensions.iter().any(|e| e == extension) {
+                        img_extensions.push(Value::from(extension));
+                    }
+                }
+            }
+        }
+        10 => {
+            // Version 11 added the options for following symbolic links and staying on one file system when scanning the collection
+            // (disabled, which corresponds to the previous behavior)
+            let user_config = index
+                .get_mut("user_config")
+                .and_then(|c| c.as_object_mut())
+                .context("User config missing in index file!")?;
+            user_config.insert("follow_symlinks".into(), Value::Bool(false));
+            user_config.insert("same_file_system".into(), Value::Bool(false));
+        }
+        11 => {
+            // Version 12 added the configuration of the checks (with all checks that existed at that time enabled, as before; checks added
+            // later are added by their own migration steps)
+            let user_config = index
+                .get_mut("user_config")
+                .and_then(|c| c.as_object_mut())
+                .context("User config missing in index file!")?;
+            let checks: BTreeMap<String, CheckConfig> = [
+                "duplicates",
+                "hashes",
+                "naming",
+                "directories",
+                "orphaned-sidecars",
+                "dangling-symlinks",
+            ]
+            .into_iter()
+            .map(|name| (name.to_string(), CheckConfig::default()))
+            .collect();
+            user_config.insert("checks".into(), serde_json::to_value(checks)?);
+        }
+        12 => {
+            // Version 13 added the decodability check, which is added with severity "ignore" (unless configured already), since decoding
+            // the whole collection is expensive and users have to opt in by changing its severity
+            let checks = index
+                .get_mut("user_config")
+                .and_then(|c| c.get_mut("checks"))
+                .and_then(|c| c.as_object_mut())
+                .context("Check configuration missing in user config!")?;
+            if !checks.contains_key("decodability") {
+                let config = CheckConfig {
+                    severity: Severity::Ignore,
+                    exempt_directories: vec![],
+                };
+                checks.insert("decodability".into(), serde_json::to_value(config)?);
+            }
+        }
+        13 => {
+            // Version 14 added the optional last_verified field to the photo entries, which is filled by the next scrub, so there is
+            // nothing to migrate.
+        }
+        14 => {
+            // Version 15 added the optional perceptual_hash field to the photo entries, which is filled by the next run of dupes, so there
+            // is nothing to migrate.
+        }
+        15 => {
+            // Version 16 replaced the directory of camera clock corrections for the photos within a directory by the hashes of these
+            // photos (so that the corrections still apply after moving the photos). Corrections that do not apply to any indexed photo
+            // are removed.
+            let photos: Vec<(PathBuf, String)> = index
+                .get("photos")
+                .and_then(|p| p.as_array())
+                .context("Photos missing in index file!")?
+                .iter()
+                .filter_map(|e| {
+                    Some((
+                        PathBuf::from(e.get("filepath")?.as_str()?),
+                        e.get("filehash")?.as_str()?.to_string(),
+                    ))
+                })
+                .collect();
+            let time_corrections = index
+                .get_mut("user_config")
+                .and_then(|c| c.get_mut("time_corrections"))
+                .and_then(|c| c.as_array_mut())
+                .context("Time corrections missing in user config!")?;
+
+            time_corrections.retain_mut(|correction| {
+                let Some(correction) = correction.as_object_mut() else {
+                    return true;
+                };
+                let Some(directory) = correction.remove("directory") else {
+                    return true;
+                };
+                let directory = PathBuf::from(directory.as_str().unwrap_or_default());
+
+                let mut hashes: Vec<&str> = photos
+                    .iter()
+                    .filter(|(p, _)| p.starts_with(&directory))
+                    .map(|(_, h)| h.as_str())
+                    .collect();
+                hashes.sort();
+                hashes.dedup();
+                if hashes.is_empty() {
+                    warn!(
+                        "Removing time correction for directory {}, since it does not apply to any indexed photo.",
+                        directory.display()
+                    );
+                    return false;
+                }
+
+                correction.insert("filehashes".into(), Value::from(hashes));
+                true
+            });
+        }
+        _ => bail!("No migration from index version {} defined!", version),
+    }
+
+    index.insert("version".into(), Value::from(version + 1));
+    Ok(())
+}
+
+/// Reads the index file for given root directory, migrating it to the current version of the index file format if it has been written by
+/// an older version of the tool. Refuses to read index files written by a newer version of the tool. Returns the index and whether it has
+/// been migrated.
+pub fn read_index_file(root_dir: &Path) -> Result<(Index, bool)> {
     let filepath = root_dir.join(INDEX_FILE_NAME);
     debug!("Reading index file at {}...", filepath.display());
     let file = File::open(&filepath)
         .with_context(|| format!("Could not open index file at {} for reading!", filepath.display()))?;
     let reader = BufReader::new(file);
-    let res = serde_json::from_reader(reader)
+    let value: Value = serde_json::from_reader(reader)
         .with_context(|| format!("Could not parse index file at {}!", filepath.display()))?;
-    Ok(res)
+
+    let Value::Object(mut map) = value else {
+        bail!(
+            "Could not parse index file at {}: Not a JSON object!",
+            filepath.display()
+        );
+    };
+
+    // Index files without a version field have been written before versioning was introduced (version 1)
+    let version = match map.get("version") {
+        Some(v) => v
+            .as_u64()
+            .with_context(|| format!("Invalid version field in index file at {}!", filepath.display()))?,
+        None => 1,
+    };
+
+    if version > INDEX_VERSION {
+        bail!(
+            "Index file at {} has version {}, but this version of the tool only supports up to version {}! Please upgrade the tool.",
+            filepath.display(),
+            version,
+            INDEX_VERSION
+        );
+    }
+
+    for v in version..INDEX_VERSION {
+        migrate_index(v, &mut map).with_context(|| {
+            format!(
+                "Could not migrate index file at {} from version {}!",
+                filepath.display(),
+                v
+            )
+        })?;
+    }
+
+    let migrated = version < INDEX_VERSION;
+    if migrated {
+        info!(
+            "Index file has been migrated from version {} to version {} (run \"update\" to write the migrated index file).",
+            version, INDEX_VERSION
+        );
+    }
+
+    let res: Index = serde_json::from_value(Value::Object(map))
+        .with_context(|| format!("Could not parse index file at {}!", filepath.display()))?;
+    res.user_config
+        .validate()
+        .with_context(|| format!("Invalid configuration in index file at {}!", filepath.display()))?;
+    Ok((res, migrated))
 }
 
-/// Writes index file to given root directory.
+/// Advisory lock on a photo collection that prevents concurrent modifications by multiple processes. The lock is held as long as this value
+/// is alive (and automatically released by the operating system if the process terminates).
+pub struct CollectionLock {
+    _file: File,
+}
+
+/// Acquires the lock for the photo collection in the given root directory. The given description of the current process (e.g., the command
+/// being run) is written to the lock file, so that other processes can name the process holding the lock if they fail to acquire it.
+pub fn lock_collection(root_dir: &Path, description: &str) -> Result<CollectionLock> {
+    let filepath = root_dir.join(LOCK_FILE_NAME);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&filepath)
+        .with_context(|| format!("Could not open lock file at {}!", filepath.display()))?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut holder = String::new();
+            file.read_to_string(&mut holder)
+                .with_context(|| format!("Could not read lock file at {}!", filepath.display()))?;
+            let holder = holder.trim();
+
+            bail!(
+                "Photo collection in {} is locked by another process ({})! Please wait for it to finish.",
+                root_dir.display(),
+                if holder.is_empty() { "unknown process" } else { holder }
+            );
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(e).with_context(|| format!("Could not lock {}!", filepath.display()));
+        }
+    }
+
+    file.set_len(0)?;
+    writeln!(file, "PID {}: {}", process::id(), description)?;
+    file.flush()?;
+    debug!("Acquired lock {}.", filepath.display());
+
+    Ok(CollectionLock { _file: file })
+}
+
+/// Writes index file to given root directory. The index is first written to a temporary file that is then atomically renamed to the actual
+/// index file, so a crash or full disk never leaves a partially written index file behind.
 pub fn write_index_file(root_dir: &Path, index: &mut Index) -> Result<()> {
     // Sort index file before writing to ensure file is stable for versioning it with Git
     index.photos.sort_unstable_by_key(|e| e.filepath.clone());
 
-    let filepath = root_dir.join(INDEX_FILE_NAME);
-    let file = File::create(&filepath)
-        .with_context(|| format!("Could not open index file at {} for writing!", filepath.display()))?;
+    let temp_filepath = root_dir.join(INDEX_TEMP_FILE_NAME);
+    let file = File::create(&temp_filepath).with_context(|| {
+        format!(
+            "Could not open temporary index file at {} for writing!",
+            temp_filepath.display()
+        )
+    })?;
     let mut writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(&mut writer, index)?;
-    writer.flush()?;
+    serde_json::to_writer_pretty(&mut writer, index)
+        .with_context(|| format!("Could not write temporary index file at {}!", temp_filepath.display()))?;
+    let file = writer.into_inner()?;
+    file.sync_all()
+        .with_context(|| format!("Could not write temporary index file at {}!", temp_filepath.display()))?;
+    drop(file);
+
+    let filepath = root_dir.join(INDEX_FILE_NAME);
+    rename(&temp_filepath, &filepath)
+        .with_context(|| format!("Could not replace index file at {}!", filepath.display()))?;
+
+    // Sync directory to make the rename durable (not possible on all platforms, hence errors are ignored)
+    if let Ok(dir) = File::open(root_dir) {
+        let _ = dir.sync_all();
+    }
+
     Ok(())
 }
@@ -0,0 +1,196 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{rename, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const JOURNAL_FILE_NAME: &str = "photo_organizer_journal.jsonl";
+const JOURNAL_TEMP_FILE_NAME: &str = "photo_organizer_journal.jsonl.tmp";
+
+/// A single record in the journal file. The journal file contains one record per line, with each operation (e.g., a run of the rename
+/// command) starting with a begin record followed by the records of all filesystem changes done by the operation.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum JournalRecord {
+    Begin { command: String, timestamp: DateTime<Utc> },
+    Change(JournalChange),
+}
+
+/// A filesystem change recorded in the journal (paths are relative to the root directory of the collection).
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalChange {
+    /// A file with the given hash has been moved
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+        filehash: String,
+    },
+
+    /// A file with the given hash has been created (e.g., copied into the collection by the import command)
+    Create { path: PathBuf, filehash: String },
+
+    /// A byte range of a file has been replaced (e.g., the EXIF segment of a JPEG file when geotagging it). The new bytes start at the
+    /// given offset and have the given length. The original bytes are stored (base64-encoded) so that the change can be reverted.
+    ReplaceBytes {
+        path: PathBuf,
+        offset: u64,
+        new_length: u64,
+        original_bytes: String,
+        original_hash: String,
+        new_hash: String,
+    },
+}
+
+/// An operation read from the journal together with all changes that it has recorded.
+pub struct JournalOperation {
+    pub command: String,
+    pub timestamp: DateTime<Utc>,
+    pub changes: Vec<JournalChange>,
+}
+
+/// Journal of a photo collection that is open for recording the changes of an operation.
+pub struct Journal {
+    filepath: PathBuf,
+    command: String,
+    file: Option<File>,
+}
+
+impl Journal {
+    /// Starts recording a new operation in the journal of the photo collection in the given root directory. The operation is only written
+    /// to the journal once its first change is recorded, so operations that do not change anything do not show up in the journal.
+    pub fn begin(root_dir: &Path, command: &str) -> Journal {
+        Journal {
+            filepath: root_dir.join(JOURNAL_FILE_NAME),
+            command: command.to_string(),
+            file: None,
+        }
+    }
+
+    /// Records the given change. This has to be called before actually changing the filesystem, so that the journal is complete even if
+    /// the process is interrupted while doing the change.
+    pub fn record(&mut self, change: JournalChange) -> Result<()> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.filepath)
+                .with_context(|| format!("Could not open journal file at {}!", self.filepath.display()))?;
+            self.file = Some(file);
+
+            self.write_record(&JournalRecord::Begin {
+                command: self.command.clone(),
+                timestamp: Utc::now(),
+            })?;
+        }
+
+        self.write_record(&JournalRecord::Change(change))
+    }
+
+    /// Appends a record to the journal file and makes sure it has been written to the disk.
+    fn write_record(&mut self, record: &JournalRecord) -> Result<()> {
+        let file = self.file.as_mut().context("Journal file not open!")?;
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())
+            .context("Could not write to journal file!")?;
+        file.sync_data().context("Could not write to journal file!")?;
+        Ok(())
+    }
+}
+
+/// Reads all operations from the journal of the photo collection in the given root directory (oldest operation first). A truncated last
+/// line (e.g., from a crash while writing the journal) is ignored.
+pub fn read_journal(root_dir: &Path) -> Result<Vec<JournalOperation>> {
+    let filepath = root_dir.join(JOURNAL_FILE_NAME);
+    if !filepath.exists() {
+        return Ok(vec![]);
+    }
+
+    debug!("Reading journal file at {}...", filepath.display());
+    let file = File::open(&filepath)
+        .with_context(|| format!("Could not open journal file at {} for reading!", filepath.display()))?;
+    let lines: Vec<String> = BufReader::new(file)
+        .lines()
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("Could not read journal file at {}!", filepath.display()))?;
+
+    let mut operations: Vec<JournalOperation> = vec![];
+    for (line_number, line) in lines.iter().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let record: JournalRecord = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(e) if line_number == lines.len() - 1 => {
+                warn!("Ignoring truncated last line of journal file: {}", e);
+                continue;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Could not parse line {} of journal file at {}!",
+                        line_number + 1,
+                        filepath.display()
+                    )
+                });
+            }
+        };
+
+        match record {
+            JournalRecord::Begin { command, timestamp } => operations.push(JournalOperation {
+                command,
+                timestamp,
+                changes: vec![],
+            }),
+            JournalRecord::Change(change) => operations
+                .last_mut()
+                .with_context(|| {
+                    format!(
+                        "Journal file at {} does not start with an operation!",
+                        filepath.display()
+                    )
+                })?
+                .changes
+                .push(change),
+        }
+    }
+
+    Ok(operations)
+}
+
+/// Replaces the journal of the photo collection in the given root directory with the given operations (e.g., after an operation has been
+/// undone). The journal file is replaced atomically like the index file.
+pub fn write_journal(root_dir: &Path, operations: &[JournalOperation]) -> Result<()> {
+    let temp_filepath = root_dir.join(JOURNAL_TEMP_FILE_NAME);
+    let file = File::create(&temp_filepath).with_context(|| {
+        format!(
+            "Could not open temporary journal file at {} for writing!",
+            temp_filepath.display()
+        )
+    })?;
+    let mut writer = BufWriter::new(file);
+
+    for operation in operations {
+        let begin = JournalRecord::Begin {
+            command: operation.command.clone(),
+            timestamp: operation.timestamp,
+        };
+        writeln!(writer, "{}", serde_json::to_string(&begin)?)?;
+
+        for change in operation.changes.iter() {
+            writeln!(
+                writer,
+                "{}",
+                serde_json::to_string(&JournalRecord::Change(change.clone()))?
+            )?;
+        }
+    }
+
+    writer.into_inner()?.sync_all()?;
+
+    let filepath = root_dir.join(JOURNAL_FILE_NAME);
+    rename(&temp_filepath, &filepath)
+        .with_context(|| format!("Could not replace journal file at {}!", filepath.display()))?;
+
+    Ok(())
+}
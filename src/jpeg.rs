@@ -0,0 +1,129 @@
+use anyhow::{bail, Context, Result};
+
+const MARKER_SOI: u8 = 0xd8;
+const MARKER_EOI: u8 = 0xd9;
+const MARKER_SOS: u8 = 0xda;
+const MARKER_APP0: u8 = 0xe0;
+const MARKER_APP1: u8 = 0xe1;
+
+/// Start of frame markers of DCT-coded images (baseline, extended sequential and progressive), which are the only ones that can be decoded
+/// by the image crate (as opposed to, e.g., lossless JPEG used for the raw image data of some RAW formats)
+const MARKERS_SOF_DCT: &[u8] = &[0xc0, 0xc1, 0xc2];
+
+/// Start of frame markers of the other coding processes (lossless, hierarchical and arithmetic coding)
+const MARKERS_SOF_OTHER: &[u8] = &[0xc3, 0xc5, 0xc6, 0xc7, 0xc9, 0xca, 0xcb, 0xcd, 0xce, 0xcf];
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Marker segment of a JPEG file, given by the marker and the byte range of the segment (including marker and length field).
+pub struct Segment {
+    pub marker: u8,
+    pub offset: usize,
+    pub length: usize,
+}
+
+/// Returns all marker segments of the given JPEG data that precede the compressed image data (i.e., up to the start of scan segment).
+pub fn read_segments(data: &[u8]) -> Result<Vec<Segment>> {
+    if data.get(0..2) != Some(&[0xff, MARKER_SOI]) {
+        bail!("Not a JPEG file (SOI marker missing)!");
+    }
+
+    let mut segments = vec![];
+    let mut pos = 2;
+
+    loop {
+        if data.get(pos) != Some(&0xff) {
+            bail!("Invalid JPEG marker at offset {}!", pos);
+        }
+
+        // Skip fill bytes
+        let mut marker_pos = pos + 1;
+        while data.get(marker_pos) == Some(&0xff) {
+            marker_pos += 1;
+        }
+
+        let marker = *data.get(marker_pos).context("Unexpected end of JPEG data!")?;
+        let length_bytes = data
+            .get(marker_pos + 1..marker_pos + 3)
+            .context("Unexpected end of JPEG data!")?;
+        let length = u16::from_be_bytes([length_bytes[0], length_bytes[1]]) as usize;
+        let end = marker_pos + 1 + length;
+
+        // Note: The length field includes itself, so it cannot be less than 2
+        if length < 2 {
+            bail!("Invalid length {} of JPEG segment at offset {}!", length, pos);
+        }
+        if end > data.len() {
+            bail!("JPEG segment at offset {} exceeds end of data!", pos);
+        }
+
+        // Note: The segment starts at the 0xff byte directly preceding the marker, so that preceding fill bytes are not part of it
+        segments.push(Segment {
+            marker,
+            offset: marker_pos - 1,
+            length: end - (marker_pos - 1),
+        });
+
+        if marker == MARKER_SOS {
+            return Ok(segments);
+        }
+
+        pos = end;
+    }
+}
+
+/// Returns whether the compressed image data of the given JPEG data is terminated by an end of image marker (missing for truncated files).
+/// Data following the marker (as appended by some cameras) is allowed.
+pub fn has_end_of_image_marker(data: &[u8]) -> Result<bool> {
+    let sos = read_segments(data)?.pop().context("No start of scan segment found!")?;
+
+    // Note: Within the compressed image data, 0xff bytes are always followed by a zero byte or a marker, so searching for the end of image
+    // marker cannot produce false positives
+    Ok(data[sos.offset + sos.length..]
+        .windows(2)
+        .any(|w| w == [0xff, MARKER_EOI]))
+}
+
+/// Returns whether the image of the given JPEG data is DCT-coded (baseline or progressive), i.e., has a start of frame segment for one of
+/// these coding processes, and can hence be decoded by the image crate.
+pub fn is_dct_coded(data: &[u8]) -> Result<bool> {
+    let frame = read_segments(data)?
+        .into_iter()
+        .find(|s| MARKERS_SOF_DCT.contains(&s.marker) || MARKERS_SOF_OTHER.contains(&s.marker))
+        .context("No start of frame segment found!")?;
+    Ok(MARKERS_SOF_DCT.contains(&frame.marker))
+}
+
+/// Returns the EXIF segment of the given JPEG data (if there is one).
+pub fn find_exif_segment(data: &[u8]) -> Result<Option<Segment>> {
+    Ok(read_segments(data)?
+        .into_iter()
+        .find(|s| s.marker == MARKER_APP1 && data[s.offset + 4..s.offset + s.length].starts_with(EXIF_HEADER)))
+}
+
+/// Returns the TIFF data contained in the given EXIF segment.
+pub fn exif_segment_tiff_data<'a>(data: &'a [u8], segment: &Segment) -> &'a [u8] {
+    &data[segment.offset + 4 + EXIF_HEADER.len()..segment.offset + segment.length]
+}
+
+/// Returns the offset at which a new EXIF segment should be inserted into the given JPEG data: Directly after the SOI marker or after the
+/// APP0 (JFIF) segment if there is one.
+pub fn exif_segment_insertion_offset(data: &[u8]) -> Result<usize> {
+    let segments = read_segments(data)?;
+    Ok(match segments.first() {
+        Some(s) if s.marker == MARKER_APP0 => s.offset + s.length,
+        _ => 2,
+    })
+}
+
+/// Creates an EXIF segment (including marker and length field) containing the given TIFF data.
+pub fn create_exif_segment(tiff_data: &[u8]) -> Result<Vec<u8>> {
+    let length = 2 + EXIF_HEADER.len() + tiff_data.len();
+    let length = u16::try_from(length).context("EXIF data exceeds maximum size of a JPEG segment!")?;
+
+    let mut segment = vec![0xff, MARKER_APP1];
+    segment.extend(length.to_be_bytes());
+    segment.extend(EXIF_HEADER);
+    segment.extend(tiff_data);
+    Ok(segment)
+}
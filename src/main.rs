@@ -1,17 +1,37 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result};
+use chrono::{Local, Utc};
+use clap::{builder::PossibleValuesParser, Parser, Subcommand};
 use log::{debug, error, info, warn};
 use std::env::current_dir;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 
+use checks::{check_names, render_findings_html};
 use collection::scan_photo_collection;
-use index::{check_index_file_is_git_versioned, get_index_root_and_subdir, read_index_file, write_index_file, Index};
+use index::{
+    check_index_file_is_git_versioned, get_index_root_and_subdir, lock_collection, read_index_file, write_index_file,
+    Index, Severity,
+};
+use output::{print_record, print_records, OutputFormat};
+use progress::format_bytes;
 
+mod bmff;
+mod cache;
 mod checks;
 mod collection;
 mod commands;
+mod heif;
+mod ignore;
 mod index;
+mod journal;
+mod jpeg;
+mod naming;
+mod output;
+mod progress;
+mod raf;
+mod tiff;
+mod video;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -23,14 +43,102 @@ struct Args {
     #[arg(long)]
     dry_run: bool,
 
+    /// Number of threads used for hashing and other parallelized work (defaults to the number of CPU cores, a low value like 1 or 2 is
+    /// recommended for collections stored on spinning disks)
+    #[arg(long, short = 'j')]
+    threads: Option<usize>,
+
+    /// Output format for the results of the list, check, dupes, scrub and update commands (JSON output is written to stdout, while log
+    /// messages are still written to stderr)
+    #[arg(long, value_enum, default_value = "text")]
+    format: OutputFormat,
+
     #[command(subcommand)]
     command: Command,
 }
 
+/// Exit code if the command succeeded but found issues (i.e., check reported findings of checks with severity "error" or scrub found
+/// files whose hash does not match)
+const EXIT_CODE_FINDINGS: u8 = 1;
+
+/// Exit code if the command could not be run due to an error
+const EXIT_CODE_ERROR: u8 = 2;
+
 #[derive(Debug, PartialEq, Subcommand)]
 enum Command {
     /// Verifies integrity of the photo collection by ensuring the index file is up-to-date and all photo hashes match their recorded hash
-    Check,
+    Check {
+        /// Only run the given checks (comma-separated), even if they are set to "ignore" in the index file
+        #[arg(long, value_delimiter = ',', value_parser = PossibleValuesParser::new(check_names()))]
+        only: Vec<String>,
+
+        /// Do not run the given checks (comma-separated)
+        #[arg(long, value_delimiter = ',', value_parser = PossibleValuesParser::new(check_names()))]
+        skip: Vec<String>,
+
+        /// Additionally write the findings as an HTML report to the given file
+        #[arg(long)]
+        html_report: Option<PathBuf>,
+    },
+
+    /// Finds visually similar photos within the current directory (e.g., resized or re-encoded copies and edited exports of a photo) by
+    /// comparing perceptual hashes and shows each cluster of similar photos side by side in a self-contained HTML file. The perceptual
+    /// hashes are stored in the index, so that only photos that have been added or modified since the last run need to be decoded.
+    Dupes {
+        /// Maximum number of differing bits (of 64) between the perceptual hashes of two photos to consider them similar (0 only finds
+        /// photos that look the same at a very low resolution, higher values find more edited versions but also more false positives).
+        /// All photos of a cluster are within this distance of each other.
+        #[arg(long, default_value = "6", value_parser = clap::value_parser!(u32).range(0..=64))]
+        threshold: u32,
+
+        /// Filename for the HTML file showing the clusters of similar photos
+        #[arg(long, default_value = "000_duplicates.html")]
+        filename: String,
+
+        #[arg(long, short)]
+        recursive: bool,
+
+        /// Width to resize images to
+        #[arg(long, default_value = "300")]
+        resize_width: u32,
+    },
+
+    /// Sets the GPS location of photos within the current directory that do not have a location yet, using the positions recorded in the
+    /// given GPX track logs at the time the photos were taken. Only JPEG files can be geotagged.
+    Geotag {
+        /// GPX files containing the track logs
+        #[arg(required = true)]
+        gpx_files: Vec<PathBuf>,
+
+        /// Maximum time between two track points to interpolate a position between them, e.g., "5m" or "30s"
+        #[arg(long, default_value = "5m")]
+        max_gap: String,
+
+        /// Offset to add to the photo timestamps before looking up the position, e.g., "+1h30m" or "-45s" (for cameras whose clock is off
+        /// and that have not been corrected by timeshift)
+        #[arg(long, default_value = "0s", allow_hyphen_values = true)]
+        time_offset: String,
+
+        /// UTC offset to assume for photos whose timestamp does not contain one, e.g., "+02:00"
+        #[arg(long, allow_hyphen_values = true)]
+        time_zone: Option<String>,
+
+        #[arg(long, short)]
+        recursive: bool,
+    },
+
+    /// Imports the photos from the given source directory (e.g., a memory card) into the collection: Photos that are already part of the
+    /// collection (by hash) are skipped, all other photos are copied into the directory given by the import directory scheme, verified
+    /// and renamed according to the naming scheme. An interrupted import can be resumed by running import again.
+    Import {
+        /// Directory to import the photos from
+        source: PathBuf,
+
+        /// Directory scheme to use instead of the one configured in the index, e.g., "%Y/%Y-%m-%d_trip" (chrono format string applied to
+        /// the photo timestamp)
+        #[arg(long)]
+        destination: Option<String>,
+    },
 
     /// Initialize new photo collection by creating an index file in the current directory
     Init,
@@ -51,15 +159,40 @@ enum Command {
         recursive: bool,
     },
 
+    /// Moves the photos within the current directory into the directories given by the directory naming scheme configured in the index
+    /// (creating them as needed) and renames them afterwards to resolve name collisions in the target directories
+    Organize {
+        #[arg(long, short)]
+        recursive: bool,
+    },
+
     /// Renames the files in the current directory (and potentially subdirectories) to follow the configured naming scheme
     Rename {
         #[arg(long, short)]
         recursive: bool,
     },
 
+    /// Verifies the hashes of the photos that have not been verified for the longest time and records the time of the verification in the
+    /// index. Running scrub regularly (e.g., nightly) with a limit verifies the whole collection over time. A summary of the verification
+    /// coverage of the collection is shown afterwards.
+    Scrub {
+        /// Maximum number of files to verify
+        #[arg(long)]
+        max_files: Option<usize>,
+
+        /// Maximum number of bytes to read, e.g., "500M" or "20G"
+        #[arg(long)]
+        max_bytes: Option<String>,
+
+        /// Maximum duration of the run, e.g., "30m" or "2h" (no further files are verified afterwards)
+        #[arg(long)]
+        max_duration: Option<String>,
+    },
+
     /// Creates a thumbnail catalogue that shows all photos within the current directory in a size-optimized thumbnail format in a
     /// self-contained HTML file. This is useful for previewing the photos, e.g., in a bandwidth-constrained setting where downloading all
-    /// the photos would not be feasible.
+    /// the photos would not be feasible. Note that HEIF images are only shown if they contain a JPEG-coded thumbnail, since HEVC-coded
+    /// images (like the HEIC files written by iPhones) cannot be decoded. Such photos are listed in the summary at the end instead.
     ThumbCat {
         /// Filename for the thumbnail catalogue
         #[arg(long, default_value = "000_thumbnails.html")]
@@ -80,14 +213,66 @@ enum Command {
         resize_width: u32,
     },
 
+    /// Corrects a wrongly set camera clock by storing a time offset in the index that is applied to the timestamps of all photos taken with
+    /// the given camera (make and/or model as shown by the list command) or, if no camera is given, of all indexed photos within the
+    /// current directory and its subdirectories (identified by their hashes, so that the correction still applies after moving them). The
+    /// photos themselves are not modified. Run rename afterwards to apply the correction to the filenames.
+    Timeshift {
+        /// Offset to add to the timestamps, e.g., "+1h30m", "-45s" or "-01:30:00"
+        #[arg(allow_hyphen_values = true)]
+        offset: String,
+
+        /// Only correct photos taken with a camera of this make
+        #[arg(long)]
+        make: Option<String>,
+
+        /// Only correct photos taken with a camera of this model
+        #[arg(long)]
+        model: Option<String>,
+    },
+
+    /// Reverts the last operation that changed files (e.g., a rename, geotag or import), moving the files back to their original location,
+    /// restoring their original contents and removing imported files after verifying their hashes
+    Undo,
+
     /// Update index file adding, renaming and deleting entries as image files have been changed
-    Update,
+    Update {
+        /// Re-hash all photos instead of trusting photos whose size and modification time match the ones recorded in the index
+        #[arg(long)]
+        rehash: bool,
+    },
+}
+
+impl Command {
+    /// Returns whether the command modifies the photo collection or the index file (if not running in dry-run mode) and thus requires
+    /// locking the collection.
+    fn modifies_collection(&self) -> bool {
+        matches!(
+            self,
+            Command::Dupes { .. }
+                | Command::Geotag { .. }
+                | Command::Import { .. }
+                | Command::Organize { .. }
+                | Command::Rename { .. }
+                | Command::Scrub { .. }
+                | Command::Timeshift { .. }
+                | Command::Undo
+                | Command::Update { .. }
+        )
+    }
 }
 
 /// Handles execution of all commands except the init command.
 fn handle_command(args: &Args, root_dir: &Path, subdir: &Path) -> Result<ExitCode> {
+    // Lock collection to prevent concurrent modifications by other processes (the lock is released when returning)
+    let _lock = if args.command.modifies_collection() && !args.dry_run {
+        Some(lock_collection(root_dir, &format!("{:?}", args.command))?)
+    } else {
+        None
+    };
+
     // Read index file and scan photo collection
-    let mut index = read_index_file(root_dir)?;
+    let (mut index, index_migrated) = read_index_file(root_dir)?;
     let mut index_changed = false;
     let photos = scan_photo_collection(&index.user_config, root_dir)?;
 
@@ -103,47 +288,246 @@ fn handle_command(args: &Args, root_dir: &Path, subdir: &Path) -> Result<ExitCod
     let mut exit_code = ExitCode::SUCCESS;
 
     match &args.command {
-        Command::Check => {
+        Command::Check {
+            only,
+            skip,
+            html_report,
+        } => {
             // Print warning is index is not up to date
-            let index_not_up_to_date = commands::update(root_dir, &mut index.clone(), &photos)?;
+            let index_not_up_to_date = commands::update(root_dir, &mut index.clone(), &photos, false)?.has_changes();
             if index_not_up_to_date {
                 warn!("Index file is not up-to-date! Consider running \"update\" before \"check\" to get accurate results.");
             }
 
-            if !commands::check(root_dir, &index) {
-                exit_code = ExitCode::FAILURE;
+            let findings = commands::check(root_dir, &index, only, skip, args.dry_run)?;
+            if args.format == OutputFormat::Text {
+                for finding in findings.iter() {
+                    match finding.severity {
+                        Severity::Error => error!("{}", finding),
+                        _ => warn!("{}", finding),
+                    }
+                }
+            } else {
+                print_records(args.format, &findings)?;
+            }
+
+            if let Some(html_report) = html_report {
+                fs::write(html_report, render_findings_html(root_dir, &findings)?)
+                    .with_context(|| format!("Could not write HTML report to {}!", html_report.display()))?;
+                info!("HTML report written to {}.", html_report.display());
+            }
+
+            if findings.iter().any(|f| f.severity == Severity::Error) {
+                exit_code = ExitCode::from(EXIT_CODE_FINDINGS);
+            }
+        }
+        Command::Dupes {
+            threshold,
+            filename,
+            recursive,
+            resize_width,
+        } => {
+            let report = commands::dupes(
+                root_dir,
+                subdir,
+                &mut index,
+                &photos,
+                *threshold,
+                filename,
+                *recursive,
+                *resize_width,
+                args.dry_run,
+            )?;
+            index_changed = report.index_changed;
+
+            if args.format == OutputFormat::Text {
+                for cluster in report.clusters.iter() {
+                    info!("Similar photos:");
+                    for photo in cluster {
+                        info!("  {} (distance {})", photo.path.display(), photo.distance);
+                    }
+                }
+                for (path, reason) in report.undecodable.iter() {
+                    debug!("{}: Could not calculate perceptual hash - {}", path.display(), reason);
+                }
+                if !report.undecodable.is_empty() {
+                    info!(
+                        "{} photos or videos could not be decoded and were skipped (run with --verbose to show them).",
+                        report.undecodable.len()
+                    );
+                }
+                info!("{} clusters of similar photos found.", report.clusters.len());
+            } else {
+                print_records(args.format, &report.clusters)?;
+            }
+        }
+        Command::Geotag {
+            gpx_files,
+            max_gap,
+            time_offset,
+            time_zone,
+            recursive,
+        } => {
+            let geotagged_photo_count = commands::geotag(
+                root_dir,
+                subdir,
+                &mut index,
+                &photos,
+                gpx_files,
+                max_gap,
+                time_offset,
+                time_zone.as_deref(),
+                *recursive,
+                args.dry_run,
+            )?;
+
+            if geotagged_photo_count > 0 {
+                index_changed = !args.dry_run;
+                if args.dry_run {
+                    info!(
+                        "{} photos would have been geotagged (running in dry-run mode).",
+                        geotagged_photo_count
+                    );
+                } else {
+                    info!("{} photos have been geotagged.", geotagged_photo_count);
+                }
+            } else {
+                info!("No photos geotagged.");
+            }
+        }
+        Command::Import { source, destination } => {
+            let imported_photo_count = commands::import(
+                root_dir,
+                &mut index,
+                &photos,
+                source,
+                destination.as_deref(),
+                args.dry_run,
+            )?;
+
+            if imported_photo_count > 0 {
+                index_changed = !args.dry_run;
+                if args.dry_run {
+                    info!(
+                        "{} photos would have been imported (running in dry-run mode).",
+                        imported_photo_count
+                    );
+                } else {
+                    info!("{} photos have been imported.", imported_photo_count);
+                }
+            } else {
+                info!("No photos imported.");
             }
         }
         Command::Init => {} // handled in main()
         Command::List { recursive } => {
             // Print warning is index is not up to date
-            let index_not_up_to_date = commands::update(root_dir, &mut index.clone(), &photos)?;
+            let index_not_up_to_date = commands::update(root_dir, &mut index.clone(), &photos, false)?.has_changes();
             if index_not_up_to_date {
                 warn!("Index file is not up-to-date! Consider running \"update\" before \"list\" to get accurate results.");
             }
 
-            commands::list(root_dir, subdir, &index, &photos, *recursive)?;
+            commands::list(root_dir, subdir, &index, &photos, *recursive, args.format)?;
         }
         Command::Map { command, recursive } => {
             // TODO: Check index up-to-date (once refactored)
-            commands::map(root_dir, subdir, &photos, *recursive, command.as_deref())?;
+            commands::map(root_dir, subdir, &index, &photos, *recursive, command.as_deref())?;
+        }
+        Command::Organize { recursive } => {
+            // Print warning is index is not up to date
+            let index_not_up_to_date = commands::update(root_dir, &mut index.clone(), &photos, false)?.has_changes();
+            if index_not_up_to_date {
+                warn!("Index file is not up-to-date! Consider running \"update\" before \"organize\" to get accurate results.");
+            }
+
+            let moved_file_count = commands::organize(root_dir, subdir, &mut index, &photos, *recursive, args.dry_run)?;
+
+            if moved_file_count > 0 {
+                index_changed = true;
+                if args.dry_run {
+                    info!(
+                        "{} photos would have been moved (running in dry-run mode).",
+                        moved_file_count
+                    );
+                } else {
+                    info!("{} photos have been moved.", moved_file_count);
+                }
+            } else {
+                info!("No photos moved.");
+            }
         }
         Command::Rename { recursive } => {
             // Print warning is index is not up to date
-            let index_not_up_to_date = commands::update(root_dir, &mut index.clone(), &photos)?;
+            let index_not_up_to_date = commands::update(root_dir, &mut index.clone(), &photos, false)?.has_changes();
             if index_not_up_to_date {
                 warn!("Index file is not up-to-date! Consider running \"update\" before \"rename\" to get accurate results.");
             }
 
-            let renamed_file_count = commands::rename(root_dir, subdir, &index, &photos, *recursive, args.dry_run)?;
+            let renamed_file_count = commands::rename(root_dir, subdir, &mut index, &photos, *recursive, args.dry_run)?;
 
             if renamed_file_count > 0 {
+                index_changed = true;
+                if args.dry_run {
+                    info!(
+                        "{} photos would have been renamed (running in dry-run mode).",
+                        renamed_file_count
+                    );
+                } else {
+                    info!("{} photos have been renamed.", renamed_file_count);
+                }
+            } else {
+                info!("No photos renamed.");
+            }
+        }
+        Command::Scrub {
+            max_files,
+            max_bytes,
+            max_duration,
+        } => {
+            // Print warning is index is not up to date
+            let index_not_up_to_date = commands::update(root_dir, &mut index.clone(), &photos, false)?.has_changes();
+            if index_not_up_to_date {
+                warn!("Index file is not up-to-date! Consider running \"update\" before \"scrub\" to get accurate results.");
+            }
+
+            let report = commands::scrub(
+                root_dir,
+                &mut index,
+                *max_files,
+                max_bytes.as_deref(),
+                max_duration.as_deref(),
+            )?;
+            index_changed = report.verified_files > 0;
+
+            if args.format == OutputFormat::Text {
+                for failure in report.failures.iter() {
+                    error!("{}: {}", failure.path.display(), failure.message);
+                }
                 info!(
-                    "{} photos have been renamed. Run \"update\" to update the index file.",
-                    renamed_file_count
+                    "{} files ({}) verified.",
+                    report.verified_files,
+                    format_bytes(report.verified_bytes)
                 );
+                info!(
+                    "Coverage: {:.1}% of {} files verified within the last {} days, {} files never verified.",
+                    report.recently_verified_percent,
+                    report.total_files,
+                    commands::SCRUB_RECENT_DAYS,
+                    report.never_verified_files
+                );
+                if let Some(oldest) = report.oldest_verification {
+                    info!(
+                        "Oldest verification: {} ({} days ago).",
+                        oldest.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+                        (Utc::now() - oldest).num_days()
+                    );
+                }
             } else {
-                info!("No photos renamed.");
+                print_record(args.format, &report)?;
+            }
+
+            if !report.failures.is_empty() {
+                exit_code = ExitCode::from(EXIT_CODE_FINDINGS);
             }
         }
         Command::ThumbCat {
@@ -154,8 +538,24 @@ fn handle_command(args: &Args, root_dir: &Path, subdir: &Path) -> Result<ExitCod
         } => {
             commands::thumbcat(root_dir, subdir, &photos, filename, *force, *recursive, *resize_width)?;
         }
-        Command::Update => {
-            index_changed = commands::update(root_dir, &mut index, &photos)?;
+        Command::Timeshift { offset, make, model } => {
+            index_changed = commands::timeshift(
+                root_dir,
+                subdir,
+                &mut index,
+                &photos,
+                offset,
+                make.as_deref(),
+                model.as_deref(),
+            )?;
+        }
+        Command::Undo => {
+            index_changed = commands::undo(root_dir, &mut index, args.dry_run)?;
+        }
+        Command::Update { rehash } => {
+            let changes = commands::update(root_dir, &mut index, &photos, *rehash)?;
+            print_record(args.format, &changes)?;
+            index_changed = changes.has_changes() || index_migrated;
         }
     }
 
@@ -176,7 +576,7 @@ fn handle_command(args: &Args, root_dir: &Path, subdir: &Path) -> Result<ExitCod
     Ok(exit_code)
 }
 
-fn main() -> Result<ExitCode> {
+fn main() -> ExitCode {
     let args = Args::parse();
 
     // Configure logger for verbosity
@@ -186,6 +586,26 @@ fn main() -> Result<ExitCode> {
         .format_timestamp(None)
         .init();
 
+    // Errors are reported with their causes (like returning them from main() would) but with a distinct exit code
+    match run(&args) {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            error!("{:?}", e);
+            ExitCode::from(EXIT_CODE_ERROR)
+        }
+    }
+}
+
+/// Runs the command given by the command line arguments.
+fn run(args: &Args) -> Result<ExitCode> {
+    // Configure global thread pool used for parallelized work
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .context("Could not configure thread pool!")?;
+    }
+
     // Get photo collection that the current working directory is a part of (required by all commands expect init)
     let found_collection = get_index_root_and_subdir(&current_dir()?)?;
 
@@ -198,7 +618,7 @@ fn main() -> Result<ExitCode> {
                     "This directory is already within the collection at: {}",
                     root_dir.display()
                 );
-                ExitCode::FAILURE
+                ExitCode::from(EXIT_CODE_ERROR)
             }
             None => {
                 let wd = current_dir()?;
@@ -210,11 +630,11 @@ fn main() -> Result<ExitCode> {
         }
     } else if let Some((ref root_dir, ref subdir)) = found_collection {
         // Handle all other commands
-        handle_command(&args, root_dir, subdir)?
+        handle_command(args, root_dir, subdir)?
     } else {
         error!("Working directory does not seem to be part of a photo collection!");
         error!("Please run \"init\" in this or the appropriate parent directory.");
-        ExitCode::FAILURE
+        ExitCode::from(EXIT_CODE_ERROR)
     };
 
     Ok(exit_code)
@@ -0,0 +1,279 @@
+use anyhow::{bail, Context, Result};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{FixedOffset, NaiveDate, TimeZone};
+use std::fmt::Write;
+use std::path::{Component, Path};
+
+/// Characters that are invalid in filenames on common filesystems (in particular on Windows and in SMB shares)
+const INVALID_FILENAME_CHARS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Token in a file naming scheme that is replaced by a property of the photo (written as %{name} or %{name:argument}).
+#[derive(Clone, Copy)]
+enum Token {
+    /// File type identifier as configured in the file types (e.g., "IMG")
+    Type,
+
+    /// Lower-cased file extension (with "jpeg" being rewritten to "jpg")
+    FileExtension,
+
+    /// Camera make from the EXIF data
+    Make,
+
+    /// Camera model from the EXIF data
+    Model,
+
+    /// Original filename (without extension) as recorded in the index
+    Orig,
+
+    /// Hash of the file as recorded in the index, optionally shortened to the given number of characters
+    Hash(Option<usize>),
+
+    /// Number of the photo among all photos in its directory (ordered by timestamp), optionally zero-padded to the given width
+    Seq(Option<usize>),
+}
+
+enum Part {
+    /// Text that is passed to chrono for formatting the photo timestamp
+    Format(String),
+    Token(Token),
+}
+
+/// Properties of a photo that are used to replace the tokens of a file naming scheme.
+pub struct NamingValues<'a> {
+    pub file_type: &'a str,
+    pub file_extension: &'a str,
+    pub make: Option<&'a str>,
+    pub model: Option<&'a str>,
+    pub orig_filename: &'a str,
+    pub filehash: Option<&'a str>,
+    pub seq: Option<usize>,
+}
+
+/// Parsed file naming scheme, consisting of chrono format strings and tokens (see Token).
+pub struct NamingScheme {
+    parts: Vec<Part>,
+}
+
+impl NamingScheme {
+    /// Parses and validates the given file naming scheme, returning an error describing the problem if the scheme contains unknown tokens,
+    /// invalid chrono format codes or characters that are invalid in filenames.
+    pub fn parse(scheme: &str) -> Result<NamingScheme> {
+        let mut parts = vec![];
+        let mut rest = scheme;
+
+        while let Some(start) = rest.find("%{") {
+            // Note: "%%{" is an escaped percent sign followed by a brace, not a token
+            let escaped = rest[..start].chars().rev().take_while(|c| *c == '%').count() % 2 == 1;
+            let end = rest[start..]
+                .find('}')
+                .with_context(|| format!("Unterminated token in file naming scheme \"{}\".", scheme))?
+                + start;
+
+            if escaped {
+                parts.push(Part::Format(rest[..start + 2].to_string()));
+                rest = &rest[start + 2..];
+                continue;
+            }
+
+            parts.push(Part::Format(rest[..start].to_string()));
+            parts.push(Part::Token(parse_token(&rest[start + 2..end])?));
+            rest = &rest[end + 1..];
+        }
+        parts.push(Part::Format(rest.to_string()));
+
+        let scheme = NamingScheme { parts };
+
+        // Validate the chrono format codes and check the characters by formatting a sample timestamp
+        let sample_values = NamingValues {
+            file_type: "IMG",
+            file_extension: "jpg",
+            make: Some("Make"),
+            model: Some("Model"),
+            orig_filename: "orig",
+            filehash: Some("0123456789abcdef"),
+            seq: Some(1),
+        };
+        let sample_name = format_sample_timestamp(&scheme.format_string(&sample_values)?)?;
+        if let Some(c) = sample_name
+            .chars()
+            .find(|c| INVALID_FILENAME_CHARS.contains(c) || c.is_control())
+        {
+            bail!(
+                "File naming scheme results in filenames containing the invalid character '{}' (e.g., \"{}\").",
+                c,
+                sample_name
+            );
+        }
+
+        Ok(scheme)
+    }
+
+    /// Returns whether the scheme contains the %{seq} token (in which case the sequence number has to be passed for formatting).
+    pub fn uses_seq(&self) -> bool {
+        self.parts.iter().any(|p| matches!(p, Part::Token(Token::Seq(_))))
+    }
+
+    /// Replaces the tokens of the scheme with the given values (sanitizing them and escaping them for chrono), returning a chrono format
+    /// string that is applied to the photo timestamp to get the filename.
+    pub fn format_string(&self, values: &NamingValues) -> Result<String> {
+        let mut res = String::new();
+
+        for part in self.parts.iter() {
+            let value = match part {
+                Part::Format(s) => {
+                    res.push_str(s);
+                    continue;
+                }
+                Part::Token(Token::Type) => values.file_type.to_string(),
+                Part::Token(Token::FileExtension) => values.file_extension.to_string(),
+                Part::Token(Token::Make) => values.make.unwrap_or("unknown").to_string(),
+                Part::Token(Token::Model) => values.model.unwrap_or("unknown").to_string(),
+                Part::Token(Token::Orig) => Path::new(values.orig_filename)
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into(),
+                Part::Token(Token::Hash(length)) => {
+                    let hash = values.filehash.context("Photo is not indexed, hash unknown.")?;
+                    hash[..length.unwrap_or(hash.len()).min(hash.len())].to_string()
+                }
+                Part::Token(Token::Seq(width)) => {
+                    let seq = values.seq.context("Sequence number of photo unknown.")?;
+                    format!("{:0width$}", seq, width = width.unwrap_or(0))
+                }
+            };
+
+            res.push_str(&sanitize_filename_part(&value).replace('%', "%%"));
+        }
+
+        Ok(res)
+    }
+}
+
+fn parse_token(token: &str) -> Result<Token> {
+    let (name, argument) = match token.split_once(':') {
+        Some((name, argument)) => {
+            let argument: usize = argument
+                .parse()
+                .ok()
+                .filter(|a| *a > 0)
+                .with_context(|| format!("Invalid argument \"{}\" for token %{{{}}}.", argument, name))?;
+            (name, Some(argument))
+        }
+        None => (token, None),
+    };
+
+    let token = match name {
+        "type" => Token::Type,
+        "fileextension" => Token::FileExtension,
+        "make" => Token::Make,
+        "model" => Token::Model,
+        "orig" => Token::Orig,
+        "hash" => return Ok(Token::Hash(argument)),
+        "seq" => return Ok(Token::Seq(argument)),
+        "counter" => bail!("Token %{{counter}} can only be used in the collision suffix."),
+        _ => bail!(
+            "Unknown token %{{{}}} (supported tokens: type, fileextension, make, model, orig, hash, seq).",
+            name
+        ),
+    };
+
+    if argument.is_some() {
+        bail!("Token %{{{}}} does not take an argument.", name);
+    }
+
+    Ok(token)
+}
+
+/// Replaces characters that are invalid in filenames on common filesystems with underscores and removes leading/trailing whitespace and
+/// trailing dots (which are not allowed on Windows).
+fn sanitize_filename_part(s: &str) -> String {
+    let sanitized: String = s
+        .chars()
+        .map(|c| {
+            if INVALID_FILENAME_CHARS.contains(&c) || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    sanitized.trim().trim_end_matches('.').to_string()
+}
+
+/// Formats a sample timestamp (including an UTC offset) with the given chrono format string, returning an error if the format string is
+/// invalid.
+fn format_sample_timestamp(fmt: &str) -> Result<String> {
+    if StrftimeItems::new(fmt).any(|item| matches!(item, Item::Error)) {
+        bail!("Invalid format code in \"{}\".", fmt);
+    }
+
+    let timestamp = FixedOffset::east_opt(0).unwrap().from_utc_datetime(
+        &NaiveDate::from_ymd_opt(2000, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap(),
+    );
+
+    let mut res = String::new();
+    write!(res, "{}", timestamp.format(fmt)).with_context(|| format!("Invalid format string \"{}\".", fmt))?;
+    Ok(res)
+}
+
+/// Validates the given file naming scheme (see NamingScheme::parse()).
+pub fn validate_file_naming_scheme(scheme: &str) -> Result<()> {
+    NamingScheme::parse(scheme).map(|_| ())
+}
+
+/// Validates the given collision suffix, which may only contain the %{counter} token and no chrono format codes.
+pub fn validate_collision_suffix(suffix: &str) -> Result<()> {
+    if let Some(c) = suffix
+        .chars()
+        .find(|c| INVALID_FILENAME_CHARS.contains(c) || c.is_control())
+    {
+        bail!(
+            "Collision suffix \"{}\" contains the invalid character '{}'.",
+            suffix,
+            c
+        );
+    }
+
+    if suffix.replace("%{counter}", "").contains('%') {
+        bail!(
+            "Collision suffix \"{}\" may not contain other tokens or format codes than %{{counter}}.",
+            suffix
+        );
+    }
+
+    Ok(())
+}
+
+/// Validates the given directory scheme (chrono format string), which has to result in a relative path within the collection without
+/// characters that are invalid in filenames.
+pub fn validate_directory_scheme(scheme: &str) -> Result<()> {
+    let sample_dir = format_sample_timestamp(scheme)?;
+
+    let sample_path = Path::new(&sample_dir);
+    if sample_dir.is_empty() || !sample_path.components().all(|c| matches!(c, Component::Normal(_))) {
+        bail!(
+            "Directory scheme \"{}\" does not result in a relative path within the collection (e.g., \"{}\").",
+            scheme,
+            sample_dir
+        );
+    }
+
+    if let Some(c) = sample_dir
+        .chars()
+        .find(|c| (*c != '/' && INVALID_FILENAME_CHARS.contains(c)) || c.is_control())
+    {
+        bail!(
+            "Directory scheme \"{}\" results in directory names containing the invalid character '{}' (e.g., \"{}\").",
+            scheme,
+            c,
+            sample_dir
+        );
+    }
+
+    Ok(())
+}
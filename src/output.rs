@@ -0,0 +1,53 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::io::{stdout, Write};
+
+/// Format in which the results of the list, check, dupes, scrub and update commands are written.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable log messages
+    Text,
+
+    /// Single JSON document written to stdout
+    Json,
+
+    /// One JSON object per line written to stdout (newline-delimited JSON)
+    Ndjson,
+}
+
+/// Writes the given records to stdout, as a JSON array (JSON format) or as one JSON object per line (NDJSON format). Nothing is written in
+/// text format, where the results are logged instead.
+pub fn print_records<T: Serialize>(format: OutputFormat, records: &[T]) -> Result<()> {
+    let mut out = stdout().lock();
+    match format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, records).context("Could not write JSON output!")?;
+            writeln!(out)?;
+        }
+        OutputFormat::Ndjson => {
+            for record in records {
+                serde_json::to_writer(&mut out, record).context("Could not write JSON output!")?;
+                writeln!(out)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the given record to stdout as a JSON object (pretty-printed in JSON format, on a single line in NDJSON format). Nothing is
+/// written in text format.
+pub fn print_record<T: Serialize>(format: OutputFormat, record: &T) -> Result<()> {
+    let mut out = stdout().lock();
+    match format {
+        OutputFormat::Text => return Ok(()),
+        OutputFormat::Json => serde_json::to_writer_pretty(&mut out, record),
+        OutputFormat::Ndjson => serde_json::to_writer(&mut out, record),
+    }
+    .context("Could not write JSON output!")?;
+    writeln!(out)?;
+
+    Ok(())
+}
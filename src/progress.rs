@@ -0,0 +1,125 @@
+use std::io::{stderr, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Tracks the progress of a long-running operation over a number of files. The counters can be updated concurrently from multiple
+/// threads (e.g., from within a rayon parallel iterator).
+pub struct Progress {
+    label: String,
+    total_files: u64,
+    total_bytes: u64,
+    done_files: AtomicU64,
+    done_bytes: AtomicU64,
+    start: Instant,
+}
+
+impl Progress {
+    /// Records that the given number of bytes has been processed.
+    pub fn add_bytes(&self, bytes: u64) {
+        self.done_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records that processing of one file has been finished.
+    pub fn finish_file(&self) {
+        self.done_files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Formats a single status line with files and bytes done, throughput and the estimated remaining time.
+    fn status_line(&self) -> String {
+        let done_files = self.done_files.load(Ordering::Relaxed);
+        let done_bytes = self.done_bytes.load(Ordering::Relaxed);
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let throughput = if elapsed > 0.0 {
+            done_bytes as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        let eta = if throughput > 0.0 && self.total_bytes >= done_bytes {
+            format_duration(((self.total_bytes - done_bytes) as f64 / throughput) as u64)
+        } else {
+            "?".into()
+        };
+
+        format!(
+            "{}: {}/{} files, {}/{}, {}/s, ETA {}",
+            self.label,
+            done_files,
+            self.total_files,
+            format_bytes(done_bytes),
+            format_bytes(self.total_bytes),
+            format_bytes(throughput as u64),
+            eta
+        )
+    }
+}
+
+/// Runs the given function while periodically printing a progress report on stderr. The report is only printed if stderr is a terminal,
+/// so redirected output (e.g., when running from cron) is not cluttered.
+pub fn with_progress<T>(label: &str, total_files: u64, total_bytes: u64, f: impl FnOnce(&Progress) -> T) -> T {
+    let progress = Progress {
+        label: label.to_string(),
+        total_files,
+        total_bytes,
+        done_files: AtomicU64::new(0),
+        done_bytes: AtomicU64::new(0),
+        start: Instant::now(),
+    };
+
+    if !stderr().is_terminal() || total_files == 0 {
+        return f(&progress);
+    }
+
+    let finished = AtomicBool::new(false);
+    let wakeup = (Mutex::new(()), Condvar::new());
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let mut guard = wakeup.0.lock().unwrap();
+            while !finished.load(Ordering::Relaxed) {
+                eprint!("\r\x1b[2K{}", progress.status_line());
+                let _ = stderr().flush();
+                guard = wakeup.1.wait_timeout(guard, REPORT_INTERVAL).unwrap().0;
+            }
+
+            // Clear progress line so it does not interfere with subsequent log output
+            eprint!("\r\x1b[2K");
+            let _ = stderr().flush();
+        });
+
+        let res = f(&progress);
+
+        finished.store(true, Ordering::Relaxed);
+        let _guard = wakeup.0.lock().unwrap();
+        wakeup.1.notify_all();
+
+        res
+    })
+}
+
+/// Formats a byte count in a human-readable way using binary prefixes.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a duration given in seconds as H:MM:SS.
+fn format_duration(secs: u64) -> String {
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
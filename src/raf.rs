@@ -0,0 +1,25 @@
+use anyhow::{Context, Result};
+
+/// Magic number at the start of Fujifilm RAF files (followed by the format version)
+const RAF_MAGIC: &[u8] = b"FUJIFILMCCD-RAW";
+
+/// Position of the offset and length (big-endian 32-bit integers) of the embedded JPEG image within the RAF header
+const JPEG_POINTER_POSITION: usize = 84;
+
+/// Returns whether the given data (the first bytes of a file) are the start of a Fujifilm RAF file. Note that RAF files are not TIFF-based,
+/// but contain a JPEG preview (including the EXIF data) whose location is given by the RAF header.
+pub fn is_raf(header: &[u8]) -> bool {
+    header.starts_with(RAF_MAGIC)
+}
+
+/// Returns the embedded JPEG image (a full-size preview that contains the EXIF data of the photo) of the given RAF file.
+pub fn read_raf_jpeg_image(data: &[u8]) -> Result<&[u8]> {
+    let pointer = data
+        .get(JPEG_POINTER_POSITION..JPEG_POINTER_POSITION + 8)
+        .context("RAF header is truncated!")?;
+    let offset = u32::from_be_bytes([pointer[0], pointer[1], pointer[2], pointer[3]]) as usize;
+    let length = u32::from_be_bytes([pointer[4], pointer[5], pointer[6], pointer[7]]) as usize;
+
+    data.get(offset..offset + length)
+        .context("Embedded JPEG image exceeds end of RAF file!")
+}
@@ -0,0 +1,437 @@
+use anyhow::{bail, Context, Result};
+
+// Field types (see TIFF 6.0 specification, section 2)
+const TYPE_BYTE: u16 = 1;
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+
+// Tags of the GPS IFD (see EXIF 2.3 specification, section 4.6.6) and the tag pointing to it from IFD0
+const TAG_GPS_IFD_POINTER: u16 = 0x8825;
+const TAG_GPS_VERSION_ID: u16 = 0x0000;
+const TAG_GPS_LATITUDE_REF: u16 = 0x0001;
+const TAG_GPS_LATITUDE: u16 = 0x0002;
+const TAG_GPS_LONGITUDE_REF: u16 = 0x0003;
+const TAG_GPS_LONGITUDE: u16 = 0x0004;
+const TAG_GPS_ALTITUDE_REF: u16 = 0x0005;
+const TAG_GPS_ALTITUDE: u16 = 0x0006;
+
+// Tags that refer to embedded JPEG images in TIFF-based RAW files (including the Panasonic-specific JpgFromRaw tag of RW2 files)
+const TAG_JPG_FROM_RAW: u16 = 0x002e;
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+const TAG_SUB_IFDS: u16 = 0x014a;
+const TAG_JPEG_INTERCHANGE_FORMAT: u16 = 0x0201;
+const TAG_JPEG_INTERCHANGE_FORMAT_LENGTH: u16 = 0x0202;
+
+// Values of the compression tag for JPEG-compressed images (old-style and new-style JPEG)
+const COMPRESSION_OLD_JPEG: u32 = 6;
+const COMPRESSION_JPEG: u32 = 7;
+
+/// Maximum number of IFDs that are followed in the IFD chain (protects against cyclic chains in corrupt files)
+const MAX_IFD_COUNT: usize = 64;
+
+/// Raw entry of an image file directory (IFD). The value field contains either the value itself (if it fits into four bytes) or the offset
+/// of the value relative to the start of the TIFF data, in the byte order of the TIFF data.
+#[derive(Clone)]
+pub struct IfdEntry {
+    pub tag: u16,
+    pub field_type: u16,
+    pub count: u32,
+    pub value: [u8; 4],
+}
+
+/// Reader for TIFF-structured data (as used by EXIF data and TIFF-based RAW formats).
+pub struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    /// Parses the header of the given TIFF data. Besides the standard magic number 42, the variants used by Olympus (ORF) and Panasonic
+    /// (RW2) RAW files are accepted.
+    pub fn new(data: &'a [u8]) -> Result<Tiff<'a>> {
+        let little_endian = match data.get(0..2) {
+            Some(b"II") => true,
+            Some(b"MM") => false,
+            _ => bail!("Invalid TIFF header!"),
+        };
+
+        let tiff = Tiff { data, little_endian };
+        match tiff.read_u16(2)? {
+            42 | 0x4f52 | 0x5352 | 0x55 => Ok(tiff),
+            magic => bail!("Invalid TIFF magic number {:#x}!", magic),
+        }
+    }
+
+    pub fn little_endian(&self) -> bool {
+        self.little_endian
+    }
+
+    /// Returns whether the data uses the standard magic number 42 (and not one of the variants used by RAW formats).
+    pub fn has_standard_magic(&self) -> bool {
+        self.read_u16(2).map(|magic| magic == 42).unwrap_or(false)
+    }
+
+    /// Returns the offset of the first IFD (IFD0).
+    pub fn first_ifd_offset(&self) -> Result<u32> {
+        self.read_u32(4)
+    }
+
+    pub fn read_u16(&self, offset: usize) -> Result<u16> {
+        let bytes = self
+            .data
+            .get(offset..offset + 2)
+            .context("Unexpected end of TIFF data!")?;
+        let bytes = [bytes[0], bytes[1]];
+        Ok(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    pub fn read_u32(&self, offset: usize) -> Result<u32> {
+        let bytes = self
+            .data
+            .get(offset..offset + 4)
+            .context("Unexpected end of TIFF data!")?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Ok(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    /// Reads the IFD at the given offset and returns its entries together with the offset of the next IFD (0 if there is none).
+    pub fn read_ifd(&self, offset: u32) -> Result<(Vec<IfdEntry>, u32)> {
+        let offset = offset as usize;
+        let count = self.read_u16(offset)? as usize;
+
+        let entries = (0..count)
+            .map(|i| {
+                let pos = offset + 2 + i * 12;
+                let value = self
+                    .data
+                    .get(pos + 8..pos + 12)
+                    .context("Unexpected end of TIFF data!")?;
+                Ok(IfdEntry {
+                    tag: self.read_u16(pos)?,
+                    field_type: self.read_u16(pos + 2)?,
+                    count: self.read_u32(pos + 4)?,
+                    value: [value[0], value[1], value[2], value[3]],
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let next_ifd_offset = self.read_u32(offset + 2 + count * 12)?;
+        Ok((entries, next_ifd_offset))
+    }
+
+    /// Returns the (first) value of an IFD entry of type SHORT or LONG, which is usually used for offsets, lengths and enumerations.
+    pub fn entry_value(&self, entry: &IfdEntry) -> Option<u32> {
+        let tiff = Tiff {
+            data: &entry.value,
+            little_endian: self.little_endian,
+        };
+        match entry.field_type {
+            TYPE_SHORT => tiff.read_u16(0).ok().map(u32::from),
+            TYPE_LONG => tiff.read_u32(0).ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the values of an IFD entry of type SHORT or LONG (e.g., the offsets of the sub-IFDs), which are either stored in the entry
+    /// itself or at the offset given by the entry.
+    pub fn entry_values(&self, entry: &IfdEntry) -> Result<Vec<u32>> {
+        let value_size = match entry.field_type {
+            TYPE_SHORT => 2,
+            TYPE_LONG => 4,
+            _ => bail!("Unexpected type {} of tag {:#x}!", entry.field_type, entry.tag),
+        };
+
+        let count = entry.count as usize;
+        if count * value_size <= 4 {
+            let inline = Tiff {
+                data: &entry.value,
+                little_endian: self.little_endian,
+            };
+            return (0..count)
+                .map(|i| match value_size {
+                    2 => inline.read_u16(i * 2).map(u32::from),
+                    _ => inline.read_u32(i * 4),
+                })
+                .collect();
+        }
+
+        let offset = self.entry_offset(entry) as usize;
+        (0..count)
+            .map(|i| match value_size {
+                2 => self.read_u16(offset + i * 2).map(u32::from),
+                _ => self.read_u32(offset + i * 4),
+            })
+            .collect()
+    }
+
+    /// Returns the value field of an IFD entry interpreted as offset (for values that do not fit into the entry).
+    pub fn entry_offset(&self, entry: &IfdEntry) -> u32 {
+        if self.little_endian {
+            u32::from_le_bytes(entry.value)
+        } else {
+            u32::from_be_bytes(entry.value)
+        }
+    }
+}
+
+/// Helper for appending IFDs to TIFF data in a given byte order.
+struct TiffWriter {
+    data: Vec<u8>,
+    little_endian: bool,
+}
+
+impl TiffWriter {
+    fn u16_bytes(&self, v: u16) -> [u8; 2] {
+        if self.little_endian {
+            v.to_le_bytes()
+        } else {
+            v.to_be_bytes()
+        }
+    }
+
+    fn u32_bytes(&self, v: u32) -> [u8; 4] {
+        if self.little_endian {
+            v.to_le_bytes()
+        } else {
+            v.to_be_bytes()
+        }
+    }
+
+    /// Creates an IFD entry with the given value, which is either stored inline or appended to the data later by append_ifd().
+    fn entry(&self, tag: u16, field_type: u16, count: u32, value: Vec<u8>) -> (IfdEntry, Vec<u8>) {
+        let mut inline = [0; 4];
+        if value.len() <= 4 {
+            inline[..value.len()].copy_from_slice(&value);
+        }
+        (
+            IfdEntry {
+                tag,
+                field_type,
+                count,
+                value: inline,
+            },
+            value,
+        )
+    }
+
+    fn rationals(&self, values: &[(u32, u32)]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|(num, denom)| [self.u32_bytes(*num), self.u32_bytes(*denom)])
+            .flatten()
+            .collect()
+    }
+
+    /// Appends an IFD with the given entries (sorted by tag) and the given next IFD offset to the data, storing values that do not fit
+    /// into the entry directly after the IFD. Returns the offset of the appended IFD.
+    fn append_ifd(&mut self, mut entries: Vec<(IfdEntry, Vec<u8>)>, next_ifd_offset: u32) -> Result<u32> {
+        entries.sort_by_key(|(e, _)| e.tag);
+
+        // IFDs have to start on a word boundary
+        if self.data.len() % 2 == 1 {
+            self.data.push(0);
+        }
+
+        let ifd_offset = self.data.len();
+        let mut value_offset = ifd_offset + 2 + entries.len() * 12 + 4;
+        let mut values = vec![];
+
+        self.data.extend(self.u16_bytes(entries.len() as u16));
+        for (mut entry, value) in entries {
+            if value.len() > 4 {
+                entry.value = self.u32_bytes(value_offset as u32);
+                value_offset += value.len() + value.len() % 2;
+                values.extend(&value);
+                if value.len() % 2 == 1 {
+                    values.push(0);
+                }
+            }
+
+            self.data.extend(self.u16_bytes(entry.tag));
+            self.data.extend(self.u16_bytes(entry.field_type));
+            self.data.extend(self.u32_bytes(entry.count));
+            self.data.extend(entry.value);
+        }
+        self.data.extend(self.u32_bytes(next_ifd_offset));
+        self.data.extend(values);
+
+        u32::try_from(ifd_offset).context("TIFF data too large!")
+    }
+}
+
+/// Returns TIFF data (as stored in the EXIF segment of a JPEG file) with a GPS IFD containing the given location and altitude. The given
+/// original TIFF data is kept unchanged and the new IFDs are appended to it, so offsets within the original data (e.g., in maker notes)
+/// stay valid: A new GPS IFD (containing the new position and all other tags of an existing GPS IFD) and a copy of IFD0 pointing to the
+/// new GPS IFD are appended, and the header is changed to point to the new IFD0. If no original TIFF data is given, new TIFF data is
+/// created.
+pub fn add_gps_location(original: Option<&[u8]>, location: (f64, f64), altitude: Option<f64>) -> Result<Vec<u8>> {
+    let (mut writer, ifd0_entries, next_ifd_offset, gps_entries) = match original {
+        Some(data) => {
+            let tiff = Tiff::new(data)?;
+            let (ifd0_entries, next_ifd_offset) = tiff.read_ifd(tiff.first_ifd_offset()?)?;
+            let gps_entries = match ifd0_entries.iter().find(|e| e.tag == TAG_GPS_IFD_POINTER) {
+                Some(e) => {
+                    let offset = tiff.entry_value(e).context("Invalid GPS IFD pointer!")?;
+                    tiff.read_ifd(offset)?.0
+                }
+                None => vec![],
+            };
+
+            let writer = TiffWriter {
+                data: data.to_vec(),
+                little_endian: tiff.little_endian(),
+            };
+            (writer, ifd0_entries, next_ifd_offset, gps_entries)
+        }
+        None => {
+            // Create minimal TIFF header (big endian) whose IFD0 offset is set below
+            let writer = TiffWriter {
+                data: b"MM\x00\x2a\x00\x00\x00\x00".to_vec(),
+                little_endian: false,
+            };
+            (writer, vec![], 0, vec![])
+        }
+    };
+
+    // Convert decimal degrees to degrees, minutes and seconds (with the seconds stored with a precision of 1/10000)
+    let to_dms = |v: f64| {
+        let v = v.abs();
+        let degrees = v.trunc();
+        let minutes = ((v - degrees) * 60.0).trunc();
+        let seconds = ((v - degrees) * 60.0 - minutes) * 60.0;
+        [
+            (degrees as u32, 1),
+            (minutes as u32, 1),
+            ((seconds * 10000.0).round() as u32, 10000),
+        ]
+    };
+
+    let (lat, long) = location;
+    let mut new_gps_entries = vec![
+        writer.entry(TAG_GPS_VERSION_ID, TYPE_BYTE, 4, vec![2, 3, 0, 0]),
+        writer.entry(
+            TAG_GPS_LATITUDE_REF,
+            TYPE_ASCII,
+            2,
+            vec![if lat >= 0.0 { b'N' } else { b'S' }, 0],
+        ),
+        writer.entry(TAG_GPS_LATITUDE, TYPE_RATIONAL, 3, writer.rationals(&to_dms(lat))),
+        writer.entry(
+            TAG_GPS_LONGITUDE_REF,
+            TYPE_ASCII,
+            2,
+            vec![if long >= 0.0 { b'E' } else { b'W' }, 0],
+        ),
+        writer.entry(TAG_GPS_LONGITUDE, TYPE_RATIONAL, 3, writer.rationals(&to_dms(long))),
+    ];
+    if let Some(alt) = altitude {
+        new_gps_entries.push(writer.entry(TAG_GPS_ALTITUDE_REF, TYPE_BYTE, 1, vec![u8::from(alt < 0.0)]));
+        new_gps_entries.push(writer.entry(
+            TAG_GPS_ALTITUDE,
+            TYPE_RATIONAL,
+            1,
+            writer.rationals(&[((alt.abs() * 100.0).round() as u32, 100)]),
+        ));
+    }
+
+    // Keep all other tags of an existing GPS IFD (their values are still valid since the original data is kept)
+    for e in gps_entries {
+        if !new_gps_entries.iter().any(|(n, _)| n.tag == e.tag) {
+            new_gps_entries.push((e, vec![]));
+        }
+    }
+
+    let gps_ifd_offset = writer.append_ifd(new_gps_entries, 0)?;
+
+    // Append copy of IFD0 that points to the new GPS IFD
+    let mut new_ifd0_entries: Vec<(IfdEntry, Vec<u8>)> = ifd0_entries
+        .into_iter()
+        .filter(|e| e.tag != TAG_GPS_IFD_POINTER)
+        .map(|e| (e, vec![]))
+        .collect();
+    new_ifd0_entries.push(writer.entry(
+        TAG_GPS_IFD_POINTER,
+        TYPE_LONG,
+        1,
+        writer.u32_bytes(gps_ifd_offset).to_vec(),
+    ));
+    let ifd0_offset = writer.append_ifd(new_ifd0_entries, next_ifd_offset)?;
+
+    let ifd0_offset_bytes = writer.u32_bytes(ifd0_offset);
+    writer.data[4..8].copy_from_slice(&ifd0_offset_bytes);
+
+    Ok(writer.data)
+}
+
+/// Returns the locations (offset and length within the given data) of the JPEG images embedded in the given TIFF-based RAW data, ordered
+/// by their length (largest first). These are the previews referenced by the JPEG tags of IFD0 and IFD1 (e.g., in CR2 and ARW files) or
+/// of the sub-IFDs (e.g., in NEF files), JPEG-compressed images stored in a single strip (e.g., in CR2 and DNG files) and the JpgFromRaw
+/// tag of RW2 files. Note that the raw image data of some formats is stored as lossless JPEG and hence returned as well, so callers have to
+/// expect images that cannot be decoded.
+pub fn find_embedded_jpegs(data: &[u8]) -> Result<Vec<(usize, usize)>> {
+    let tiff = Tiff::new(data)?;
+
+    // Collect the IFDs of the IFD chain and their sub-IFDs
+    let mut ifds = vec![];
+    let mut sub_ifd_offsets = vec![];
+    let mut offset = tiff.first_ifd_offset()?;
+    while offset != 0 && ifds.len() < MAX_IFD_COUNT {
+        let (entries, next_ifd_offset) = tiff.read_ifd(offset)?;
+        for entry in entries.iter().filter(|e| e.tag == TAG_SUB_IFDS) {
+            sub_ifd_offsets.extend(tiff.entry_values(entry)?);
+        }
+        ifds.push(entries);
+        offset = next_ifd_offset;
+    }
+    for offset in sub_ifd_offsets.into_iter().take(MAX_IFD_COUNT) {
+        if let Ok((entries, _)) = tiff.read_ifd(offset) {
+            ifds.push(entries);
+        }
+    }
+
+    let mut jpegs = vec![];
+    for entries in ifds.iter() {
+        let find_entry = |tag: u16| entries.iter().find(|e| e.tag == tag);
+        let value = |tag: u16| find_entry(tag).and_then(|e| tiff.entry_value(e));
+
+        if let (Some(offset), Some(length)) = (
+            value(TAG_JPEG_INTERCHANGE_FORMAT),
+            value(TAG_JPEG_INTERCHANGE_FORMAT_LENGTH),
+        ) {
+            jpegs.push((offset as usize, length as usize));
+        }
+
+        let single_strip = find_entry(TAG_STRIP_OFFSETS).map(|e| e.count == 1).unwrap_or(false);
+        if single_strip && matches!(value(TAG_COMPRESSION), Some(COMPRESSION_OLD_JPEG | COMPRESSION_JPEG)) {
+            if let (Some(offset), Some(length)) = (value(TAG_STRIP_OFFSETS), value(TAG_STRIP_BYTE_COUNTS)) {
+                jpegs.push((offset as usize, length as usize));
+            }
+        }
+
+        if let Some(entry) = find_entry(TAG_JPG_FROM_RAW) {
+            jpegs.push((tiff.entry_offset(entry) as usize, entry.count as usize));
+        }
+    }
+
+    jpegs.retain(|(offset, length)| {
+        data.get(*offset..offset.saturating_add(*length))
+            .map(|jpeg| jpeg.starts_with(&[0xff, 0xd8]))
+            .unwrap_or(false)
+    });
+    jpegs.sort_unstable_by_key(|(offset, length)| (std::cmp::Reverse(*length), *offset));
+    jpegs.dedup();
+
+    Ok(jpegs)
+}
@@ -0,0 +1,230 @@
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+use crate::bmff::{container_payload, find_box, full_box_payload, parse_boxes, read_top_level_box, read_u32, read_u64};
+use crate::collection::{PhotoMetaData, PhotoTimestamp};
+
+/// Seconds between the epoch used for timestamps in ISO base media files (1904-01-01) and the Unix epoch
+const BMFF_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+/// Properties read from the movie header box.
+struct MovieHeader {
+    creation_time: u64,
+    timescale: u32,
+    duration: u64,
+}
+
+/// Reads the meta data of a video in an ISO base media file (MP4 or QuickTime MOV): Creation time and duration from the movie header,
+/// dimensions from the track headers, and camera and location from the QuickTime user data or the Apple meta data keys.
+///
+/// Note: The creation time in the movie header is specified to be in UTC, but the local time of the recording is unknown. Unless the video
+/// contains an Apple creation date (which includes the UTC offset), the creation time is hence converted to the local time zone of the
+/// computer running this tool.
+pub fn read_video_metadata(filepath: &Path) -> Result<PhotoMetaData> {
+    let mut file = File::open(filepath)
+        .with_context(|| format!("Could not open {} for reading video meta data!", filepath.display()))?;
+    let moov = read_top_level_box(&mut file, b"moov")
+        .with_context(|| format!("Could not read video meta data from {}!", filepath.display()))?
+        .with_context(|| format!("No movie box found in {}!", filepath.display()))?;
+
+    let header = match find_box(&moov, &[b"mvhd"])? {
+        Some(mvhd) => Some(parse_movie_header(mvhd)?),
+        None => None,
+    };
+    let user_data = read_user_data(&moov)?;
+    let apple_keys = read_apple_metadata(&moov)?;
+
+    // Prefer the Apple creation date since it contains the UTC offset
+    let timestamp = match apple_keys.get("com.apple.quicktime.creationdate") {
+        Some(s) => DateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%z")
+            .ok()
+            .map(|ts| PhotoTimestamp {
+                local: ts.naive_local(),
+                offset: Some(*ts.offset()),
+            }),
+        None => None,
+    };
+    let timestamp = timestamp.or_else(|| {
+        header
+            .as_ref()
+            .filter(|h| h.creation_time > 0)
+            .and_then(|h| i64::try_from(h.creation_time).ok())
+            .and_then(|t| t.checked_sub(BMFF_EPOCH_OFFSET))
+            .and_then(|t| Utc.timestamp_opt(t, 0).single())
+            .map(|ts| {
+                let ts = ts.with_timezone(&Local);
+                PhotoTimestamp {
+                    local: ts.naive_local(),
+                    offset: Some(*ts.offset()),
+                }
+            })
+    });
+
+    let duration = header
+        .as_ref()
+        .filter(|h| h.timescale > 0)
+        .map(|h| h.duration as f64 / h.timescale as f64);
+
+    let make = apple_keys
+        .get("com.apple.quicktime.make")
+        .or_else(|| user_data.get(b"\xa9mak"))
+        .cloned();
+    let model = apple_keys
+        .get("com.apple.quicktime.model")
+        .or_else(|| user_data.get(b"\xa9mod"))
+        .cloned();
+
+    let location = apple_keys
+        .get("com.apple.quicktime.location.ISO6709")
+        .or_else(|| user_data.get(b"\xa9xyz"))
+        .and_then(|s| parse_iso6709(s));
+
+    Ok(PhotoMetaData {
+        make,
+        model,
+        timestamp,
+        location: location.map(|(lat, long, _)| (lat, long)),
+        altitude: location.and_then(|(_, _, alt)| alt),
+        orientation: None,
+        dimensions: read_dimensions(&moov)?,
+        duration,
+    })
+}
+
+fn parse_movie_header(mvhd: &[u8]) -> Result<MovieHeader> {
+    let (version, data) = full_box_payload(mvhd)?;
+    Ok(if version == 1 {
+        MovieHeader {
+            creation_time: read_u64(data, 0)?,
+            timescale: read_u32(data, 16)?,
+            duration: read_u64(data, 20)?,
+        }
+    } else {
+        MovieHeader {
+            creation_time: read_u32(data, 0)? as u64,
+            timescale: read_u32(data, 8)?,
+            duration: read_u32(data, 12)? as u64,
+        }
+    })
+}
+
+/// Returns the dimensions of the first track with a non-zero width and height (i.e., the first video track).
+fn read_dimensions(moov: &[u8]) -> Result<Option<(u32, u32)>> {
+    for trak in parse_boxes(moov)?.into_iter().filter(|b| &b.box_type == b"trak") {
+        let Some(tkhd) = find_box(trak.payload, &[b"tkhd"])? else {
+            continue;
+        };
+
+        // Width and height are stored as 16.16 fixed-point numbers at the end of the track header
+        let (_, data) = full_box_payload(tkhd)?;
+        if data.len() < 8 {
+            continue;
+        }
+        let width = read_u32(data, data.len() - 8)? >> 16;
+        let height = read_u32(data, data.len() - 4)? >> 16;
+
+        if width > 0 && height > 0 {
+            return Ok(Some((width, height)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Reads the string items (e.g., "©xyz" for the location as written by Android and QuickTime) from the user data box.
+fn read_user_data(moov: &[u8]) -> Result<HashMap<[u8; 4], String>> {
+    let mut items = HashMap::new();
+    let Some(udta) = find_box(moov, &[b"udta"])? else {
+        return Ok(items);
+    };
+
+    for item in parse_boxes(udta)?.into_iter().filter(|b| b.box_type[0] == 0xa9) {
+        // Items are either stored as QuickTime text (length, language and text) or iTunes-style with a data box
+        let value = match find_box(item.payload, &[b"data"]).ok().flatten() {
+            Some(data) => data.get(8..),
+            None => read_u32(item.payload, 0)
+                .ok()
+                .and_then(|v| item.payload.get(4..4 + (v >> 16) as usize)),
+        };
+
+        if let Some(value) = value {
+            let value = String::from_utf8_lossy(value).trim_end_matches('\0').trim().to_string();
+            items.insert(item.box_type, value);
+        }
+    }
+
+    Ok(items)
+}
+
+/// Reads the string values of the Apple meta data keys (e.g., "com.apple.quicktime.creationdate") as written by iPhones.
+fn read_apple_metadata(moov: &[u8]) -> Result<HashMap<String, String>> {
+    let mut values = HashMap::new();
+    let Some(meta) = parse_boxes(moov)?.into_iter().find(|b| &b.box_type == b"meta") else {
+        return Ok(values);
+    };
+    let meta = container_payload(&meta);
+
+    let (Some(keys), Some(ilst)) = (find_box(meta, &[b"keys"])?, find_box(meta, &[b"ilst"])?) else {
+        return Ok(values);
+    };
+
+    // Key names are stored in the keys box and referenced by their (1-based) index from the items in the item list box
+    let (_, keys) = full_box_payload(keys)?;
+    let key_count = read_u32(keys, 0)?;
+    let mut key_names = vec![];
+    let mut pos = 4;
+    for _ in 0..key_count {
+        let size = read_u32(keys, pos)? as usize;
+        let name = keys.get(pos + 8..pos + size).context("Invalid meta data key!")?;
+        key_names.push(String::from_utf8_lossy(name).into_owned());
+        pos += size;
+    }
+
+    for item in parse_boxes(ilst)? {
+        let key_index = u32::from_be_bytes(item.box_type) as usize;
+        let (Some(name), Some(data)) = (
+            key_index.checked_sub(1).and_then(|i| key_names.get(i)),
+            find_box(item.payload, &[b"data"])?,
+        ) else {
+            continue;
+        };
+
+        // The data box starts with the value type (1 for UTF-8 strings) and the locale
+        if read_u32(data, 0)? == 1 {
+            let value = data.get(8..).unwrap_or_default();
+            values.insert(name.clone(), String::from_utf8_lossy(value).into_owned());
+        }
+    }
+
+    Ok(values)
+}
+
+/// Parses a location in the ISO 6709 format as used by videos (e.g., "+48.8577+002.2950+035.000/"), returning latitude, longitude and the
+/// optional altitude.
+fn parse_iso6709(s: &str) -> Option<(f64, f64, Option<f64>)> {
+    let s = s.trim().trim_end_matches('/');
+
+    // Split into the signed components
+    let mut components = vec![];
+    let mut start = 0;
+    for (i, c) in s.char_indices().skip(1) {
+        if c == '+' || c == '-' {
+            components.push(&s[start..i]);
+            start = i;
+        }
+    }
+    components.push(&s[start..]);
+
+    let lat: f64 = components.first()?.parse().ok()?;
+    let long: f64 = components.get(1)?.parse().ok()?;
+    let alt: Option<f64> = components.get(2).and_then(|a| a.parse().ok());
+
+    if lat.abs() > 90.0 || long.abs() > 180.0 {
+        return None;
+    }
+
+    Some((lat, long, alt))
+}